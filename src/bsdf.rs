@@ -0,0 +1,299 @@
+use core::*;
+use scene::toplevel::*;
+use surface::*;
+use sampling::*;
+use integrator::*;
+use microfacet::*;
+
+use std::f32::consts::PI;
+
+/// Orthonormal shading frame, `n` is the local +z.
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub s : Vec3<f32>,
+    pub t : Vec3<f32>,
+    pub n : Vec3<f32>
+}
+
+impl Frame {
+    pub fn new(n : Vec3<f32>) -> Frame {
+        let (s, t) = orthonormal_basis(n);
+        Frame { s : s, t : t, n : n }
+    }
+
+    pub fn to_local(&self, v : Vec3<f32>) -> Vec3<f32> {
+        Vec3::new(v.dot(&self.s), v.dot(&self.t), v.dot(&self.n))
+    }
+
+    pub fn to_world(&self, v : Vec3<f32>) -> Vec3<f32> {
+        self.s * v.x + self.t * v.y + self.n * v.z
+    }
+}
+
+/// Direction picked by `Bsdf::sample`. `weight` is `eval / pdf`, so it multiplies the path throughput.
+#[derive(Clone, Copy, Debug)]
+pub struct BsdfSample {
+    pub wi       : Vec3<f32>,
+    pub weight   : Spectrum,
+    pub pdf      : f32,
+    /// Picked from a perfectly specular lobe, `pdf` is meaningless then.
+    pub specular : bool
+}
+
+/// How a surface scatters light, in the local shading frame: +z is the normal,
+/// `wo` points toward the viewer and `wi` toward the light.
+pub trait Bsdf {
+    /// `f(wo, wi) * cos(wi)` of the non-specular lobes.
+    fn eval(&self, wo : Vec3<f32>, wi : Vec3<f32>) -> Spectrum;
+
+    /// Solid angle density of `sample` picking `wi`, without the specular lobes.
+    fn pdf(&self, wo : Vec3<f32>, wi : Vec3<f32>) -> f32;
+
+    /// Incoming direction for the uniform numbers `u`, `u.z` picks the lobe.
+    fn sample(&self, wo : Vec3<f32>, u : Vec3<f32>) -> Option<BsdfSample>;
+
+    /// Perfectly specular directions with their weights, for Whitted style tracing.
+    fn specular(&self, _wo : Vec3<f32>) -> Vec<(Vec3<f32>, Spectrum)> {
+        Vec::new()
+    }
+
+    /// Has only specular lobes, so sampling lights for it is pointless.
+    fn is_specular(&self) -> bool {
+        false
+    }
+
+    /// Has a narrow lobe that Whitted tracing should follow with a sampled ray.
+    fn is_glossy(&self) -> bool {
+        false
+    }
+}
+
+pub type BoxedBsdf = Box<Bsdf>;
+
+fn mirror(wo : Vec3<f32>) -> Vec3<f32> {
+    Vec3::new(-wo.x, -wo.y, wo.z)
+}
+
+/// What the material trait can tell: a Lambertian `albedo` with a mirror on top
+/// reflecting the `reflect` part of the light, the diffuse part gets what the mirror
+/// leaves. Every material without a `Surface` is shaded through it, by every integrator.
+#[derive(Clone, Copy, Debug)]
+pub struct MaterialBsdf {
+    pub albedo  : Vec3<f32>,
+    pub reflect : f32
+}
+
+impl Bsdf for MaterialBsdf {
+    fn eval(&self, wo : Vec3<f32>, wi : Vec3<f32>) -> Spectrum {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        self.albedo * ((1.0 - self.reflect) * wi.z / PI)
+    }
+
+    fn pdf(&self, wo : Vec3<f32>, wi : Vec3<f32>) -> f32 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        (1.0 - self.reflect) * wi.z / PI
+    }
+
+    fn sample(&self, wo : Vec3<f32>, u : Vec3<f32>) -> Option<BsdfSample> {
+        if u.z < self.reflect {
+            return Some(BsdfSample {
+                wi       : mirror(wo),
+                weight   : Vec3::new(1.0, 1.0, 1.0),
+                pdf      : self.reflect,
+                specular : true
+            });
+        }
+        let wi = cosine_hemisphere(u.x, u.y);
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return None;
+        }
+        // cosine sampling cancels with the cosine and the PI of the brdf
+        Some(BsdfSample {
+            wi       : wi,
+            weight   : self.albedo,
+            pdf      : self.pdf(wo, wi),
+            specular : false
+        })
+    }
+
+    fn specular(&self, wo : Vec3<f32>) -> Vec<(Vec3<f32>, Spectrum)> {
+        if self.reflect > 0.0 {
+            vec![(mirror(wo), Vec3::new(1.0, 1.0, 1.0) * self.reflect)]
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+/// Smooth glass or water, `wo.z < 0` means the ray is inside.
+#[derive(Clone, Copy, Debug)]
+pub struct DielectricBsdf {
+    pub ior  : f32,
+    pub tint : Vec3<f32>
+}
+
+impl DielectricBsdf {
+    /// Reflectance, reflected and refracted directions, the last one is None on total internal reflection.
+    pub fn split(&self, wo : Vec3<f32>) -> (f32, Vec3<f32>, Option<Vec3<f32>>) {
+        let (eta_i, eta_t, n) = if wo.z > 0.0 {
+            (1.0, self.ior, Vec3::new(0.0, 0.0, 1.0))
+        } else {
+            (self.ior, 1.0, Vec3::new(0.0, 0.0, -1.0))
+        };
+        let cos_i = wo.z.abs();
+        let kr = fresnel_dielectric(cos_i, eta_i, eta_t);
+        let refl = n * (2.0 * cos_i) - wo;
+        let eta = eta_i / eta_t;
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+        if kr >= 1.0 || sin2_t > 1.0 {
+            return (1.0, refl, None);
+        }
+        let cos_t = (1.0 - sin2_t).sqrt();
+        (kr, refl, Some((wo * -eta + n * (eta * cos_i - cos_t)).normalize()))
+    }
+}
+
+impl Bsdf for DielectricBsdf {
+    fn eval(&self, _ : Vec3<f32>, _ : Vec3<f32>) -> Spectrum {
+        Vec3::new(0.0, 0.0, 0.0)
+    }
+
+    fn pdf(&self, _ : Vec3<f32>, _ : Vec3<f32>) -> f32 {
+        0.0
+    }
+
+    fn sample(&self, wo : Vec3<f32>, u : Vec3<f32>) -> Option<BsdfSample> {
+        let (kr, refl, refr) = self.split(wo);
+        Some(match refr {
+            Some(refr) if u.z >= kr =>
+                BsdfSample { wi : refr, weight : self.tint, pdf : 1.0 - kr, specular : true },
+            _ => BsdfSample { wi : refl, weight : Vec3::new(1.0, 1.0, 1.0), pdf : kr, specular : true }
+        })
+    }
+
+    fn specular(&self, wo : Vec3<f32>) -> Vec<(Vec3<f32>, Spectrum)> {
+        match self.split(wo) {
+            (kr, refl, Some(refr)) =>
+                vec![(refl, Vec3::new(1.0, 1.0, 1.0) * kr), (refr, self.tint * (1.0 - kr))],
+            (_, refl, None) => vec![(refl, Vec3::new(1.0, 1.0, 1.0))]
+        }
+    }
+
+    fn is_specular(&self) -> bool {
+        true
+    }
+}
+
+/// Size in uv of the pixel around the hit of a ray with differentials, 0 for other rays.
+/// The rays through the neighbouring pixels are crossed with the tangent plane
+/// of the hit, and the uv there follow from how they change next to the hit.
+fn uv_footprint(ray : &Ray, hit : &SurfaceHit) -> f32 {
+    let d = match ray.differentials {
+        Some(d) => d,
+        None => return 0.0
+    };
+    let intersect = &hit.intersect;
+    let frame = Frame::new(intersect.normal);
+    // where a neighbour ray crosses the tangent plane, relative to the hit
+    let offset = |pos : Vec3<f32>, dir : Vec3<f32>| {
+        let cos = dir.dot(&frame.n);
+        if cos.abs() < 1e-6 {
+            None
+        } else {
+            Some(pos + dir * ((intersect.pos - pos).dot(&frame.n) / cos) - intersect.pos)
+        }
+    };
+    let (dx, dy) = match (offset(d.x_pos, d.x_dir), offset(d.y_pos, d.y_dir)) {
+        (Some(dx), Some(dy)) => (dx, dy),
+        (Some(o), None) | (None, Some(o)) => (o, o),
+        (None, None) => return 0.0
+    };
+
+    // change of the uv per unit along the tangent, probed a step away on both sides
+    // with a short ray along the normal against the object hit only
+    let step = (dx.norm().max(dy.norm()) * 0.01).max(ray_epsilon(intersect.pos) * 10.0);
+    let probe = |tangent : Vec3<f32>, side : f32| {
+        let probe = Ray::new(intersect.pos + tangent * side + frame.n * step, frame.n * -1.0).clip(2.0 * step);
+        match hit.object.intersect(probe) {
+            Some((t, other)) if probe.contains(t) => Some((other.uv - intersect.uv) / side),
+            _ => None
+        }
+    };
+    let rate = |tangent : Vec3<f32>| {
+        // a uv seam only shows as a jump on one side, keep the smaller change
+        match (probe(tangent, step), probe(tangent, -step)) {
+            (Some(a), Some(b)) => Vec2::new(if a.x.abs() < b.x.abs() { a.x } else { b.x },
+                                            if a.y.abs() < b.y.abs() { a.y } else { b.y }),
+            (Some(a), None) | (None, Some(a)) => a,
+            (None, None) => Vec2::new(0.0, 0.0)
+        }
+    };
+    let (ds, dt) = (rate(frame.s), rate(frame.t));
+    let width = |o : Vec3<f32>| (ds * o.dot(&frame.s) + dt * o.dot(&frame.t)).norm();
+    width(dx).max(width(dy))
+}
+
+/// Scattering at a hit of `ray`, with the shading frame in world space.
+/// Objects without a surface are shaded by their materials through `MaterialBsdf`.
+/// None for emissive surfaces, which only glow.
+pub fn surface_bsdf(ray : &Ray, hit : &SurfaceHit) -> Option<(Frame, BoxedBsdf)> {
+    let intersect = &hit.intersect;
+    // reflecting surfaces are shaded from the side the ray came from
+    let facing = if ray.dir.dot(&intersect.normal) > 0.0 { intersect.normal * -1.0 } else { intersect.normal };
+    match hit.surface {
+        Some(&Surface::Emissive { .. }) => None,
+        Some(&Surface::Dielectric { ior, tint }) =>
+            Some((Frame::new(intersect.normal), Box::new(DielectricBsdf { ior : ior, tint : tint }))),
+        Some(&Surface::Principled(ref principled)) => {
+            let width = if principled.needs_footprint() { uv_footprint(ray, hit) } else { 0.0 };
+            let bsdf = principled.at(intersect.uv, width);
+            // transmitting ones need to know whether the ray is inside, like glass
+            let n = if bsdf.transmission > 0.0 { intersect.normal } else { facing };
+            Some((Frame::new(n), Box::new(bsdf)))
+        }
+        Some(&Surface::Textured(ref color)) => {
+            let width = if color.needs_footprint() { uv_footprint(ray, hit) } else { 0.0 };
+            let bsdf = MaterialBsdf { albedo : color.at(intersect.uv, width), reflect : 0.0 };
+            Some((Frame::new(facing), Box::new(bsdf)))
+        }
+        Some(glossy) => Glossy::new(glossy).map(|g| (Frame::new(facing), Box::new(g) as BoxedBsdf)),
+        None => {
+            let bsdf = MaterialBsdf {
+                albedo  : intersect.material.get_color(intersect),
+                reflect : intersect.material.get_reflect()
+            };
+            Some((Frame::new(facing), Box::new(bsdf)))
+        }
+    }
+}
+
+#[test]
+fn test_bsdf() {
+    let frame = Frame::new(Vec3::new(0.0, 0.6, 0.8));
+    let v = Vec3::new(0.3, -0.2, 0.9);
+    assert!((frame.to_world(frame.to_local(v)) - v).norm() < 0.0001);
+    assert!((frame.to_local(frame.n) - Vec3::new(0.0, 0.0, 1.0)).norm() < 0.0001);
+
+    let wo = Vec3::new(0.6, 0.0, 0.8);
+    let diffuse = MaterialBsdf { albedo : Vec3::new(0.5, 0.5, 0.5), reflect : 0.25 };
+    let sample = diffuse.sample(wo, Vec3::new(0.3, 0.7, 0.9)).unwrap();
+    assert!(!sample.specular && sample.wi.z > 0.0);
+    assert!((sample.weight - diffuse.eval(wo, sample.wi) / sample.pdf).norm() < 0.0001);
+    let sample = diffuse.sample(wo, Vec3::new(0.3, 0.7, 0.1)).unwrap();
+    assert!(sample.specular && (sample.wi - Vec3::new(-0.6, 0.0, 0.8)).norm() < 0.0001);
+    // the mirror takes its part of the albedo
+    assert!((diffuse.eval(wo, wo) * PI - Vec3::new(0.3, 0.3, 0.3)).norm() < 0.0001);
+
+    // energy is split between reflection and refraction
+    let glass = DielectricBsdf { ior : 1.5, tint : Vec3::new(1.0, 1.0, 1.0) };
+    let lobes = glass.specular(wo);
+    assert_eq!(lobes.len(), 2);
+    assert!((lobes[0].1.x + lobes[1].1.x - 1.0).abs() < 0.0001);
+    assert!(lobes[1].0.z < 0.0);
+    // and grazing from inside is totally reflected
+    assert_eq!(glass.specular(Vec3::new(0.9, 0.0, -0.1).normalize()).len(), 1);
+}
@@ -1,5 +1,22 @@
 use na::*;
+use rand::*;
 use core::ray::*;
+use sampling::*;
+
+use std::f32::consts::PI;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    /// Pinhole camera, the only one that honors `aperture`.
+    Perspective,
+    /// Parallel rays, the argument is the view height in world units.
+    Orthographic(f32),
+    /// Equidistant fisheye: angle from the view direction grows linearly
+    /// with the distance from the image center, `fov` is covered vertically.
+    Fisheye,
+    /// Full 360x180 lat-long panorama, `fov` is ignored.
+    Equirectangular
+}
 
 pub struct Camera {
     pub pos     : Vec3<f32>,
@@ -7,19 +24,102 @@ pub struct Camera {
     pub up      : Vec3<f32>,
     pub fov     : f32,
     pub w       : i32,
-    pub h       : i32
+    pub h       : i32,
+    /// Lens radius, 0.0 gives a pinhole camera with everything in focus.
+    pub aperture   : f32,
+    /// Distance along the view direction to the plane in perfect focus.
+    pub focus_dist : f32,
+    pub projection : Projection
 }
 
 impl Camera {
-    pub fn get_ray(&self, x : i32, y : i32) -> Ray {
+    /// Orthonormal camera basis: (forward, right, up).
+    fn basis(&self) -> (Vec3<f32>, Vec3<f32>, Vec3<f32>) {
         let dir = (self.look_at - self.pos).normalize();
         let right = (dir.cross(&self.up)).normalize();
-        let dx = (x - self.w / 2) as f32;
-        let dy = (y - self.h / 2) as f32;
+        let up = right.cross(&dir);
+        (dir, right, up)
+    }
+
+    /// Ray through the image point (x, y), in pixels. Coordinates are
+    /// continuous: (0, 0) is the top left corner of the image, pixel
+    /// centers are at (i + 0.5, j + 0.5).
+    /// `fov` is the vertical field of view in degrees, horizontal one
+    /// follows from the aspect ratio.
+    ///
+    /// Ignores the lens, see `get_lens_ray` for depth of field.
+    pub fn get_pinhole_ray(&self, x : f32, y : f32) -> Ray {
+        let (dir, right, up) = self.basis();
+        let aspect = self.w as f32 / self.h as f32;
+        // image point in [-1, 1] of the image height
+        let px = (2.0 * x / self.w as f32 - 1.0) * aspect;
+        let py = 1.0 - 2.0 * y / self.h as f32;
+        match self.projection {
+            Projection::Perspective => {
+                let scale = (self.fov.to_radians() / 2.0).tan();
+                Ray::new(self.pos, (dir + up * (py * scale) + right * (px * scale)).normalize())
+            }
+            Projection::Orthographic(height) =>
+                Ray::new(self.pos + (up * py + right * px) * (height / 2.0), dir),
+            Projection::Fisheye => {
+                let r = (px * px + py * py).sqrt();
+                let theta = r * self.fov.to_radians() / 2.0;
+                let phi = py.atan2(px);
+                Ray::new(self.pos, (dir * theta.cos() +
+                                    (right * phi.cos() + up * phi.sin()) * theta.sin()).normalize())
+            }
+            Projection::Equirectangular => {
+                let lon = (x / self.w as f32 - 0.5) * 2.0 * PI;
+                let lat = (0.5 - y / self.h as f32) * PI;
+                Ray::new(self.pos, (dir * (lat.cos() * lon.cos()) +
+                                    right * (lat.cos() * lon.sin()) +
+                                    up * lat.sin()).normalize())
+            }
+        }
+    }
+
+    /// Ray through the image point (x, y), see `get_pinhole_ray`.
+    /// With non zero `aperture` the ray starts at a random point of the lens,
+    /// so several samples per pixel are needed to get a smooth image. Renderers
+    /// pick the lens point from their own sampler with `get_lens_ray` instead.
+    pub fn get_ray(&self, x : f32, y : f32) -> Ray {
+        if self.aperture > 0.0 {
+            self.get_lens_ray(x, y, Vec2::new(random::<f32>(), random::<f32>()))
+        } else {
+            self.get_lens_ray(x, y, Vec2::new(0.5, 0.5))
+        }
+    }
+
+    /// Thin lens model: `lens` is a point of the unit square, mapped onto
+    /// the lens disk. All rays through the same image point converge
+    /// on the focal plane. The rays through the neighbouring pixels, for the
+    /// same point of the lens, come along as the ray differentials.
+    pub fn get_lens_ray(&self, x : f32, y : f32, lens : Vec2<f32>) -> Ray {
+        let ray = self.lens_ray(x, y, lens);
+        let (rx, ry) = (self.lens_ray(x + 1.0, y, lens), self.lens_ray(x, y + 1.0, lens));
         Ray {
-            pos : self.pos,
-            dir : (dir - self.up * dy * 0.006 + right * dx * 0.006).normalize()
+            differentials : Some(Differentials {
+                x_pos : rx.pos,
+                x_dir : rx.dir,
+                y_pos : ry.pos,
+                y_dir : ry.dir
+            }),
+            .. ray
+        }
+    }
+
+    fn lens_ray(&self, x : f32, y : f32, lens : Vec2<f32>) -> Ray {
+        let pinhole = self.get_pinhole_ray(x, y);
+        if self.aperture <= 0.0 || self.projection != Projection::Perspective {
+            return pinhole;
         }
+
+        let (dir, right, up) = self.basis();
+        let pinhole = pinhole.dir;
+        let focus = self.pos + pinhole * (self.focus_dist / pinhole.dot(&dir));
+        let disk = sample_disk(lens.x, lens.y) * self.aperture;
+        let pos = self.pos + right * disk.x + up * disk.y;
+        Ray::new(pos, (focus - pos).normalize())
     }
 
 }
@@ -27,18 +127,67 @@ impl Camera {
 
 #[test]
 fn test_camera() {
-    let camera = Camera {
-        pos     : Vec3::new(-10.0, 0.0, 0.0),
-        look_at : Vec3::new(0.0, 0.0, 0.0),
-        up      : Vec3::new(0.0, 1.0, 0.0),
-        fov     : 60.0,
-        w       : 300,
-        h       : 200
-    };
-    assert_eq!(camera.get_ray(150, 100).pos, camera.pos);
-    assert_eq!(camera.get_ray(150, 100).dir, Vec3::new(1.0, 0.0, 0.0));
-    assert!(camera.get_ray(100, 100).dir.z < 0.0);
-    assert!(camera.get_ray(200, 100).dir.z > 0.0);
-    assert!(camera.get_ray(150, 10).dir.y > 0.0);
-    assert!(camera.get_ray(150, 190).dir.y < 0.0);
+    use testing::*;
+
+    let camera = gen_test_camera(300, 200);
+    assert_eq!(camera.get_ray(150.0, 100.0).pos, camera.pos);
+    assert_eq!(camera.get_ray(150.0, 100.0).dir, Vec3::new(1.0, 0.0, 0.0));
+    assert!(camera.get_ray(100.0, 100.0).dir.z < 0.0);
+    assert!(camera.get_ray(200.0, 100.0).dir.z > 0.0);
+    assert!(camera.get_ray(150.0, 10.0).dir.y > 0.0);
+    assert!(camera.get_ray(150.0, 190.0).dir.y < 0.0);
+}
+
+#[test]
+fn test_camera_fov() {
+    use testing::*;
+
+    let small = gen_test_camera(400, 300);
+    let big = gen_test_camera(1600, 1200);
+    let a = small.get_ray(0.0, 0.0).dir;
+    let b = big.get_ray(0.0, 0.0).dir;
+    assert!((a - b).norm() < 0.0001);
+
+    // top edge of the image is exactly fov / 2 above the view direction
+    let top = small.get_ray(200.0, 0.0).dir;
+    assert!((top.y.atan2(top.x).to_degrees() - 30.0).abs() < 0.001);
+}
+
+#[test]
+fn test_camera_lens() {
+    use testing::*;
+
+    let mut camera = gen_test_camera(300, 200);
+    camera.aperture = 0.5;
+    let focus = Vec3::new(0.0, 0.0, 0.0);
+    for &(u, v) in &[(0.0, 0.0), (1.0, 0.3), (0.2, 0.9)] {
+        let ray = camera.get_lens_ray(150.0, 100.0, Vec2::new(u, v));
+        assert!((ray.pos - camera.pos).norm() <= 0.5 + 0.0001);
+        // every lens sample of the center pixel hits the point in focus
+        let t = (focus.x - ray.pos.x) / ray.dir.x;
+        assert!((ray.pos + ray.dir * t - focus).norm() < 0.0001);
+    }
+}
+
+#[test]
+fn test_camera_projections() {
+    use testing::*;
+
+    let mut camera = gen_test_camera(300, 200);
+
+    camera.projection = Projection::Orthographic(4.0);
+    let ray = camera.get_ray(0.0, 0.0);
+    assert_eq!(ray.dir, Vec3::new(1.0, 0.0, 0.0));
+    assert!((ray.pos - Vec3::new(-10.0, 2.0, -3.0)).norm() < 0.0001);
+
+    camera.projection = Projection::Fisheye;
+    assert_eq!(camera.get_ray(150.0, 100.0).dir, Vec3::new(1.0, 0.0, 0.0));
+    let top = camera.get_ray(150.0, 0.0).dir;
+    assert!((top.y.atan2(top.x).to_degrees() - 30.0).abs() < 0.001);
+
+    camera.projection = Projection::Equirectangular;
+    assert!((camera.get_ray(150.0, 100.0).dir - Vec3::new(1.0, 0.0, 0.0)).norm() < 0.0001);
+    assert!((camera.get_ray(0.0, 100.0).dir - Vec3::new(-1.0, 0.0, 0.0)).norm() < 0.0001);
+    assert!((camera.get_ray(75.0, 100.0).dir - Vec3::new(0.0, 0.0, -1.0)).norm() < 0.0001);
+    assert!(camera.get_ray(150.0, 0.0).dir.y > 0.9999);
 }
@@ -12,14 +12,27 @@ pub struct IntersectData {
 
 pub trait Intersectable {
     fn intersect(&self, Ray) -> Option<(f32, IntersectData)>;
-}
 
-#[allow(dead_code)]
-pub fn gen_test_intersectable() -> IntersectData {
-    IntersectData {
-        normal   : Vec3::new(0.0, 1.0, 0.0),
-        pos      : Vec3::new(0.0, 0.0, 0.0),
-        uv       : Vec2::new(0.0, 0.0),
-        material : test_mat()
+    /// Is there any hit inside the ray extent and closer than `t_max`. Made for
+    /// shadow rays: aggregates stop on the first hit found and skip the shading data.
+    /// By default the ray is shifted to `t_min` like for `intersect` in the scene,
+    /// and the full hit is built; the primitives have no cheaper override yet.
+    fn occluded(&self, r : Ray, t_max : f32) -> bool {
+        let r = r.clip(t_max).shifted();
+        match self.intersect(r) {
+            Some((t, _)) => r.contains(t),
+            None => false
+        }
     }
 }
+
+#[test]
+fn test_occluded_extent() {
+    use testing::*;
+
+    // the wall before t_min hides the one inside the extent, unless the ray is shifted
+    let walls = Walls(vec![0.0, 2.0]);
+    let ray = Ray { t_min : 1.5, .. Ray::new(Vec3::new(-1.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)) };
+    assert!(walls.occluded(ray, 10.0));
+    assert!(!walls.occluded(ray, 2.5));
+}
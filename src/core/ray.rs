@@ -1,29 +1,208 @@
 use core::*;
 
+use std::f32::MAX;
+
+/// Self intersection offset of secondary rays, relative to the scale of the hit position.
+pub const RAY_EPSILON : f32 = 1e-4;
+
+/// Offset that keeps a ray off the surface at `pos`: it follows the magnitude
+/// of the coordinates, so it works for tiny and huge scenes.
+pub fn ray_epsilon(pos : Vec3<f32>) -> f32 {
+    RAY_EPSILON * pos.x.abs().max(pos.y.abs()).max(pos.z.abs()).max(1e-3)
+}
+
+/// Rays through the next pixel to the right and the next one down, carried along
+/// from the camera. Where they hit tells how much of a surface one pixel covers.
+#[derive(Clone, Debug, Copy)]
+pub struct Differentials {
+    pub x_pos : Vec3<f32>,
+    pub x_dir : Vec3<f32>,
+    pub y_pos : Vec3<f32>,
+    pub y_dir : Vec3<f32>
+}
+
+/// Half-line `pos + dir * t`, only hits with `t` in [t_min, t_max] count.
 #[derive(Clone, Debug, Copy)]
 pub struct Ray {
-    pub pos      : Vec3<f32>,
-    pub dir      : Vec3<f32>
+    pub pos           : Vec3<f32>,
+    pub dir           : Vec3<f32>,
+    pub t_min         : f32,
+    pub t_max         : f32,
+    /// Camera rays have them, `bounce` keeps them over specular bounces, others start without.
+    pub differentials : Option<Differentials>
 }
 
 impl Ray {
+    pub fn new(pos : Vec3<f32>, dir : Vec3<f32>) -> Ray {
+        Ray {
+            pos           : pos,
+            dir           : dir,
+            t_min         : 0.0,
+            t_max         : MAX,
+            differentials : None
+        }
+    }
+
+    /// Ray leaving a surface at `pos`, skipping the surface itself by `ray_epsilon`.
+    pub fn spawn(pos : Vec3<f32>, dir : Vec3<f32>) -> Ray {
+        Ray {
+            t_min : ray_epsilon(pos),
+            .. Ray::new(pos, dir)
+        }
+    }
+
+    /// Same ray, ending at `t_max` if it is closer.
+    pub fn clip(&self, t_max : f32) -> Ray {
+        Ray {
+            t_max : self.t_max.min(t_max),
+            .. *self
+        }
+    }
+
+    /// Same ray starting at `t_min`, for objects that know nothing about the extent:
+    /// they don't report the surface a secondary ray leaves, and still find the far
+    /// side when the ray starts inside them. Distances along it are `t - t_min`.
+    /// The primitives are all such objects for now, none of them checks the extent.
+    pub fn shifted(&self) -> Ray {
+        Ray {
+            pos   : self.pos + self.dir * self.t_min,
+            t_min : 0.0,
+            t_max : self.t_max - self.t_min,
+            .. *self
+        }
+    }
+
+    pub fn contains(&self, t : f32) -> bool {
+        t >= self.t_min && t <= self.t_max
+    }
+
     pub fn reflect(&self, intersect_pos : Vec3<f32>, intersect_norm : Vec3<f32>) -> Ray{
+        Ray::spawn(intersect_pos,
+                   self.dir - intersect_norm * (self.dir.dot(&intersect_norm) * 2.0))
+    }
+
+    /// Ray leaving the surface at `pos` with normal `n` along `dir`, the perfectly specular
+    /// reflection or refraction of this one. The neighbour rays are crossed with the tangent
+    /// plane and turned with it: mirrored about `n` on reflection, and keeping their
+    /// offset from this ray's direction on refraction.
+    pub fn bounce(&self, pos : Vec3<f32>, n : Vec3<f32>, dir : Vec3<f32>) -> Ray {
+        let reflected = self.dir.dot(&n) * dir.dot(&n) < 0.0;
+        let turn = |d : Vec3<f32>| if reflected { d - n * (d.dot(&n) * 2.0) } else { dir + (d - self.dir) };
+        let cross = |p : Vec3<f32>, d : Vec3<f32>| {
+            let cos = d.dot(&n);
+            if cos.abs() < 1e-6 { None } else { Some(p + d * ((pos - p).dot(&n) / cos)) }
+        };
+        let differentials = self.differentials.and_then(|d| {
+            match (cross(d.x_pos, d.x_dir), cross(d.y_pos, d.y_dir)) {
+                (Some(x_pos), Some(y_pos)) => Some(Differentials {
+                    x_pos : x_pos,
+                    x_dir : turn(d.x_dir),
+                    y_pos : y_pos,
+                    y_dir : turn(d.y_dir)
+                }),
+                _ => None
+            }
+        });
         Ray {
-            pos : intersect_pos,
-            dir : self.dir - intersect_norm * (self.dir.dot(&intersect_norm) * 2.0)
+            differentials : differentials,
+            .. Ray::spawn(pos, dir)
+        }
+    }
+
+    /// Snell's law. `intersect_norm` faces the side the ray comes from,
+    /// `eta` is the ratio of refraction indices, incoming over outgoing.
+    /// None on total internal reflection.
+    pub fn refract(&self, intersect_pos : Vec3<f32>, intersect_norm : Vec3<f32>, eta : f32) -> Option<Ray> {
+        let cos_i = -self.dir.dot(&intersect_norm);
+        let sin2_t = eta * eta * (1.0 - cos_i * cos_i).max(0.0);
+        if sin2_t > 1.0 {
+            return None;
         }
+        let cos_t = (1.0 - sin2_t).sqrt();
+        Some(Ray::spawn(intersect_pos,
+                        (self.dir * eta + intersect_norm * (eta * cos_i - cos_t)).normalize()))
+    }
+}
+
+/// Exact Fresnel reflectance of unpolarized light on a dielectric interface,
+/// `cos_i` is the cosine of the incident angle on the `eta_i` side.
+pub fn fresnel_dielectric(cos_i : f32, eta_i : f32, eta_t : f32) -> f32 {
+    let sin_t = eta_i / eta_t * (1.0 - cos_i * cos_i).max(0.0).sqrt();
+    if sin_t >= 1.0 {
+        return 1.0;
     }
+    let cos_t = (1.0 - sin_t * sin_t).max(0.0).sqrt();
+    let r_par = (eta_t * cos_i - eta_i * cos_t) / (eta_t * cos_i + eta_i * cos_t);
+    let r_perp = (eta_i * cos_i - eta_t * cos_t) / (eta_i * cos_i + eta_t * cos_t);
+    (r_par * r_par + r_perp * r_perp) / 2.0
 }
 
 
 #[test]
 fn test_reflect() {
-    let ray = Ray {
-        pos : Vec3::new(10.0, 0.0, 0.0),
-        dir :  Vec3::new(1.0, 0.0, 0.0)
-    };
+    let ray = Ray::new(Vec3::new(10.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
     assert_eq!(ray.reflect(Vec3::new(10.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0)).dir,
                Vec3::new(-1.0, 0.0, 0.0));
 
 
 }
+
+#[test]
+fn test_extent() {
+    let ray = Ray::spawn(Vec3::new(100.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+    assert!(!ray.contains(0.0));
+    assert!(ray.contains(0.1));
+    let small = Ray::spawn(Vec3::new(0.001, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0));
+    assert!(small.contains(0.00001));
+    assert!(!ray.clip(5.0).contains(6.0));
+    assert!(!ray.clip(5.0).clip(10.0).contains(6.0));
+}
+
+#[test]
+fn test_refract() {
+    let normal = Vec3::new(0.0, 1.0, 0.0);
+    let pos = Vec3::new(0.0, 0.0, 0.0);
+    let straight = Ray::new(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+    assert!((straight.refract(pos, normal, 1.0 / 1.5).unwrap().dir - straight.dir).norm() < 0.0001);
+
+    // 45 degrees into glass: sin(t) = sin(45) / 1.5
+    let dir = Vec3::new(1.0, -1.0, 0.0).normalize();
+    let refr = Ray::new(Vec3::new(-1.0, 1.0, 0.0), dir).refract(pos, normal, 1.0 / 1.5).unwrap();
+    assert!((refr.dir.x - (0.5f32).sqrt() / 1.5).abs() < 0.0001);
+    assert!(refr.dir.y < 0.0);
+
+    // and back out of the glass at the same angle is past the critical one
+    let inside = Ray::new(Vec3::new(-1.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 0.0).normalize());
+    assert!(inside.refract(pos, normal * -1.0, 1.5).is_none());
+}
+
+#[test]
+fn test_bounce() {
+    let offset = Vec3::new(0.1, 0.0, 0.0);
+    let dir = Vec3::new(1.0, -1.0, 0.0).normalize();
+    let ray = Ray {
+        differentials : Some(Differentials { x_pos : offset, x_dir : dir, y_pos : Vec3::new(0.0, 0.0, 0.0), y_dir : dir }),
+        .. Ray::new(Vec3::new(0.0, 0.0, 0.0), dir)
+    };
+    let normal = Vec3::new(0.0, 1.0, 0.0);
+    let pos = Vec3::new(1.0, -1.0, 0.0);
+    let mirror = ray.bounce(pos, normal, ray.reflect(pos, normal).dir);
+    let d = mirror.differentials.unwrap();
+    // parallel rays stay parallel off a flat mirror, and hit it as far apart as they started
+    assert!((d.x_dir - mirror.dir).norm() < 0.0001);
+    assert!((d.x_pos - (pos + offset)).norm() < 0.0001 && (d.y_pos - pos).norm() < 0.0001);
+
+    let refr = ray.refract(pos, normal, 1.0 / 1.5).unwrap();
+    let d = ray.bounce(pos, normal, refr.dir).differentials.unwrap();
+    assert!((d.x_dir - refr.dir).norm() < 0.0001);
+    assert!(Ray::new(pos, dir).bounce(pos, normal, refr.dir).differentials.is_none());
+}
+
+#[test]
+fn test_fresnel() {
+    // 4% at normal incidence on glass
+    assert!((fresnel_dielectric(1.0, 1.0, 1.5) - 0.04).abs() < 0.0001);
+    assert!((fresnel_dielectric(1.0, 1.5, 1.0) - 0.04).abs() < 0.0001);
+    assert_eq!(fresnel_dielectric(0.5, 1.5, 1.0), 1.0);
+    assert!(fresnel_dielectric(0.01, 1.0, 1.5) > 0.9);
+}
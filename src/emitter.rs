@@ -0,0 +1,138 @@
+use na::*;
+
+use sampling::*;
+
+use std::fs::File;
+use std::io::Read;
+use std::f32::consts::PI;
+
+/// Shape of a glowing scene object, for sampling points on it by area.
+/// Scene objects only answer ray queries, so the emitter keeps its own copy of the geometry.
+pub enum Emitter {
+    /// Center, radius.
+    Sphere(Vec3<f32>, f32),
+    /// Triangles with the running sum of their areas.
+    Mesh(Vec<[Vec3<f32>; 3]>, Vec<f32>)
+}
+
+/// Density per solid angle, seen from `from`, of a point sampled uniformly over `area`
+/// that landed at `p` with the normal `n`. Both sides glow.
+pub fn solid_angle_pdf(area : f32, from : Vec3<f32>, p : Vec3<f32>, n : Vec3<f32>) -> f32 {
+    let to_light = p - from;
+    let dist2 = to_light.sqnorm();
+    let cos_l = n.dot(&to_light).abs() / dist2.sqrt();
+    dist2 / (cos_l.max(1e-6) * area)
+}
+
+fn triangle_area(t : &[Vec3<f32>; 3]) -> f32 {
+    (t[1] - t[0]).cross(&(t[2] - t[0])).norm() / 2.0
+}
+
+/// Triangles of a wavefront .obj text, faces with more vertices are split into fans.
+/// Vertices are placed like `load_mesh` places them: scaled, rotated, then moved to `pos`.
+pub fn parse_obj(text : &str, pos : Vec3<f32>, scale : Vec3<f32>, rot : &Rot3<f32>)
+                 -> Result<Vec<[Vec3<f32>; 3]>, String> {
+    let mut vertices = Vec::new();
+    let mut triangles = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let mut items = line.split_whitespace();
+        match items.next() {
+            Some("v") => {
+                let v = items.take(3).map(|x| x.parse::<f32>()).collect::<Result<Vec<f32>, _>>();
+                match v {
+                    Ok(ref v) if v.len() == 3 =>
+                        vertices.push(*rot * (Vec3::new(v[0], v[1], v[2]) * scale) + pos),
+                    _ => return Err(format!("line {}: bad vertex", n + 1))
+                }
+            }
+            Some("f") => {
+                // `v`, `v/vt`, `v/vt/vn` or `v//vn`, negative indices count from the end
+                let face = items.map(|x| {
+                    x.split('/').next().unwrap().parse::<i64>().ok().and_then(|i| {
+                        let i = if i < 0 { vertices.len() as i64 + i } else { i - 1 };
+                        vertices.get(i as usize).cloned()
+                    })
+                }).collect::<Option<Vec<Vec3<f32>>>>();
+                match face {
+                    Some(ref face) if face.len() >= 3 => for i in 1 .. face.len() - 1 {
+                        triangles.push([face[0], face[i], face[i + 1]]);
+                    },
+                    _ => return Err(format!("line {}: bad face", n + 1))
+                }
+            }
+            _ => ()
+        }
+    }
+    Ok(triangles)
+}
+
+impl Emitter {
+    pub fn mesh(triangles : Vec<[Vec3<f32>; 3]>) -> Emitter {
+        let mut cdf = Vec::with_capacity(triangles.len());
+        let mut sum = 0.0;
+        for t in &triangles {
+            sum += triangle_area(t);
+            cdf.push(sum);
+        }
+        Emitter::Mesh(triangles, cdf)
+    }
+
+    /// Emitter of the .obj file, transformed like `load_mesh` does.
+    pub fn load_mesh(path : &str, pos : Vec3<f32>, scale : Vec3<f32>, rot : &Rot3<f32>) -> Result<Emitter, String> {
+        let mut text = String::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_string(&mut text)).map_err(|e| e.to_string()));
+        Ok(Emitter::mesh(try!(parse_obj(&text, pos, scale, rot))))
+    }
+
+    pub fn area(&self) -> f32 {
+        match *self {
+            Emitter::Sphere(_, radius) => 4.0 * PI * radius * radius,
+            Emitter::Mesh(_, ref cdf) => cdf.last().cloned().unwrap_or(0.0)
+        }
+    }
+
+    /// Point uniformly distributed over the surface, with the surface normal there.
+    pub fn sample(&self, u : Vec2<f32>) -> (Vec3<f32>, Vec3<f32>) {
+        match *self {
+            Emitter::Sphere(pos, radius) => {
+                let n = uniform_sphere(u.x, u.y);
+                (pos + n * radius, n)
+            }
+            Emitter::Mesh(ref triangles, ref cdf) => {
+                // pick a triangle by area, then reuse `u.x` inside it
+                let x = u.x * self.area();
+                let i = match cdf.binary_search_by(|a| a.partial_cmp(&x).unwrap()) {
+                    Ok(i) | Err(i) => i.min(triangles.len() - 1)
+                };
+                let lo = if i > 0 { cdf[i - 1] } else { 0.0 };
+                let s = ((x - lo) / (cdf[i] - lo).max(1e-12)).min(1.0);
+                let t = &triangles[i];
+                let r = s.sqrt();
+                let p = t[0] * (1.0 - r) + t[1] * (r * (1.0 - u.y)) + t[2] * (r * u.y);
+                (p, (t[1] - t[0]).cross(&(t[2] - t[0])).normalize())
+            }
+        }
+    }
+}
+
+#[test]
+fn test_emitter() {
+    let rot = Rot3::new(Vec3::new(0.0, 0.0, 0.0));
+    let quad = parse_obj("v 0 0 0\nv 2 0 0\nv 2 0 1\nv 0 0 1\nf 1 2/1 3//1 -1\n",
+                         Vec3::new(0.0, 5.0, 0.0), Vec3::new(1.0, 1.0, 1.0), &rot).unwrap();
+    assert_eq!(quad.len(), 2);
+    let emitter = Emitter::mesh(quad);
+    assert!((emitter.area() - 2.0).abs() < 0.0001);
+    for &u in &[Vec2::new(0.1, 0.2), Vec2::new(0.7, 0.9), Vec2::new(0.99, 0.01)] {
+        let (p, n) = emitter.sample(u);
+        assert!((p.y - 5.0).abs() < 0.0001 && p.x >= 0.0 && p.x <= 2.0 && p.z >= 0.0 && p.z <= 1.0);
+        assert!(n.y.abs() > 0.9999);
+    }
+    // straight above, the quad covers `area / dist^2` steradians
+    let pdf = solid_angle_pdf(emitter.area(), Vec3::new(1.0, 0.0, 0.5), Vec3::new(1.0, 5.0, 0.5), Vec3::new(0.0, -1.0, 0.0));
+    assert!((pdf - 12.5).abs() < 0.0001);
+
+    let sphere = Emitter::Sphere(Vec3::new(1.0, 0.0, 0.0), 2.0);
+    let (p, n) = sphere.sample(Vec2::new(0.3, 0.6));
+    assert!((p - Vec3::new(1.0, 0.0, 0.0) - n * 2.0).norm() < 0.0001);
+}
@@ -0,0 +1,281 @@
+use na::*;
+
+use std::fs::File;
+use std::io::Read;
+use std::f32::consts::PI;
+
+/// Light coming from infinitely far away in every direction, stored as
+/// an equirectangular (lat-long) HDR image: +y is up, the center of the image looks along +z.
+/// Radiance of 1.0 lights a white diffuse surface to white.
+pub struct EnvMap {
+    w        : usize,
+    h        : usize,
+    pixels   : Vec<Vec3<f32>>,
+    /// Turn around the up axis, in radians.
+    rotation : f32,
+    /// Cumulative distribution of the rows, `h + 1` values from 0 to 1.
+    rows     : Vec<f32>,
+    /// Cumulative distribution inside every row, `w + 1` values per row.
+    cols     : Vec<f32>
+}
+
+fn luminance(c : Vec3<f32>) -> f32 {
+    c.x * 0.2126 + c.y * 0.7152 + c.z * 0.0722
+}
+
+/// Running sums of `weights` scaled to end at 1, uniform if all of them are zero.
+fn build_cdf(weights : &[f32]) -> Vec<f32> {
+    let mut cdf = Vec::with_capacity(weights.len() + 1);
+    cdf.push(0.0);
+    for w in weights {
+        let last = cdf[cdf.len() - 1];
+        cdf.push(last + w);
+    }
+    let total = cdf[weights.len()];
+    for (i, x) in cdf.iter_mut().enumerate() {
+        *x = if total > 0.0 { *x / total } else { i as f32 / weights.len() as f32 };
+    }
+    cdf
+}
+
+/// Index of the interval of `cdf` containing `u`, and the position inside it.
+fn find_interval(cdf : &[f32], u : f32) -> (usize, f32) {
+    let (mut lo, mut hi) = (0, cdf.len() - 1);
+    while hi - lo > 1 {
+        let mid = (lo + hi) / 2;
+        if cdf[mid] <= u {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    let width = cdf[lo + 1] - cdf[lo];
+    (lo, if width > 0.0 { ((u - cdf[lo]) / width).min(0.9999) } else { 0.5 })
+}
+
+fn rgbe(p : &[u8]) -> Vec3<f32> {
+    if p[3] == 0 {
+        Vec3::new(0.0, 0.0, 0.0)
+    } else {
+        let f = (p[3] as f32 - 136.0).exp2();
+        Vec3::new(p[0] as f32 * f, p[1] as f32 * f, p[2] as f32 * f)
+    }
+}
+
+/// Radiance `.hdr` file: header, resolution line and flat or run length encoded RGBE scanlines.
+fn parse_hdr(bytes : &[u8]) -> Result<(usize, usize, Vec<Vec3<f32>>), String> {
+    let mut pos = 0;
+    let mut line = || -> Option<String> {
+        let start = pos;
+        while pos < bytes.len() && bytes[pos] != b'\n' {
+            pos += 1;
+        }
+        if pos >= bytes.len() {
+            return None;
+        }
+        pos += 1;
+        Some(String::from_utf8_lossy(&bytes[start .. pos - 1]).into_owned())
+    };
+
+    match line() {
+        Some(ref magic) if magic.starts_with("#?") => (),
+        _ => return Err("not a radiance hdr file".to_string())
+    }
+    loop {
+        match line() {
+            None => return Err("unexpected end of header".to_string()),
+            Some(ref l) if l.is_empty() => break,
+            Some(ref l) if l.starts_with("FORMAT=") && l != "FORMAT=32-bit_rle_rgbe" =>
+                return Err(format!("unsupported format `{}`", &l[7 ..])),
+            _ => ()
+        }
+    }
+    let res = try!(line().ok_or("missing resolution".to_string()));
+    let parts = res.split_whitespace().collect::<Vec<&str>>();
+    let (h, w) = match (parts.len(), parts.get(0), parts.get(2)) {
+        (4, Some(&"-Y"), Some(&"+X")) => match (parts[1].parse::<usize>(), parts[3].parse::<usize>()) {
+            (Ok(h), Ok(w)) => (h, w),
+            _ => return Err(format!("bad resolution `{}`", res))
+        },
+        _ => return Err(format!("unsupported orientation `{}`", res))
+    };
+    let mut data = &bytes[pos ..];
+
+    let eof = || "unexpected end of pixel data".to_string();
+    let mut pixels = Vec::with_capacity(w * h);
+    let mut scanline = vec![0u8; w * 4];
+    for _ in 0 .. h {
+        let rle = w >= 8 && w < 32768 && data.len() >= 4 && data[0] == 2 && data[1] == 2 && data[2] & 0x80 == 0;
+        if !rle {
+            if data.len() < w * 4 {
+                return Err(eof());
+            }
+            for x in 0 .. w {
+                pixels.push(rgbe(&data[x * 4 .. x * 4 + 4]));
+            }
+            data = &data[w * 4 ..];
+            continue;
+        }
+        if ((data[2] as usize) << 8 | data[3] as usize) != w {
+            return Err("scanline width mismatch".to_string());
+        }
+        data = &data[4 ..];
+        // channels are stored one after another, each as runs and literal spans
+        for c in 0 .. 4 {
+            let mut x = 0;
+            while x < w {
+                if data.is_empty() {
+                    return Err(eof());
+                }
+                let count = data[0] as usize;
+                if count > 128 {
+                    let count = count - 128;
+                    if data.len() < 2 || x + count > w {
+                        return Err(eof());
+                    }
+                    for i in 0 .. count {
+                        scanline[(x + i) * 4 + c] = data[1];
+                    }
+                    data = &data[2 ..];
+                    x += count;
+                } else {
+                    if count == 0 || data.len() < count + 1 || x + count > w {
+                        return Err(eof());
+                    }
+                    for i in 0 .. count {
+                        scanline[(x + i) * 4 + c] = data[1 + i];
+                    }
+                    data = &data[count + 1 ..];
+                    x += count;
+                }
+            }
+        }
+        for x in 0 .. w {
+            pixels.push(rgbe(&scanline[x * 4 .. x * 4 + 4]));
+        }
+    }
+    Ok((w, h, pixels))
+}
+
+impl EnvMap {
+    /// Environment from `w * h` pixels, row by row from the top, scaled by `intensity`.
+    pub fn new(w : usize, h : usize, pixels : Vec<Vec3<f32>>, intensity : f32, rotation : f32) -> EnvMap {
+        let pixels = pixels.into_iter().map(|p| p * intensity).collect::<Vec<Vec3<f32>>>();
+        let mut cols = Vec::with_capacity(h * (w + 1));
+        let mut row_weights = Vec::with_capacity(h);
+        for y in 0 .. h {
+            // rows near the poles cover less of the sphere
+            let sin_theta = ((y as f32 + 0.5) / h as f32 * PI).sin();
+            let weights = pixels[y * w .. (y + 1) * w].iter()
+                .map(|p| luminance(*p) * sin_theta)
+                .collect::<Vec<f32>>();
+            row_weights.push(weights.iter().fold(0.0, |a, b| a + b));
+            cols.extend(build_cdf(&weights));
+        }
+        EnvMap {
+            w        : w,
+            h        : h,
+            pixels   : pixels,
+            rotation : rotation,
+            rows     : build_cdf(&row_weights),
+            cols     : cols
+        }
+    }
+
+    pub fn load(path : &str, intensity : f32, rotation : f32) -> Result<EnvMap, String> {
+        let mut bytes = Vec::new();
+        try!(File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)).map_err(|e| e.to_string()));
+        let (w, h, pixels) = try!(parse_hdr(&bytes));
+        Ok(EnvMap::new(w, h, pixels, intensity, rotation))
+    }
+
+    /// Image coordinates in [0, 1) of the direction.
+    fn to_uv(&self, dir : Vec3<f32>) -> (f32, f32) {
+        let dir = dir.normalize();
+        let lon = dir.x.atan2(dir.z) - self.rotation;
+        let u = (lon / (2.0 * PI) + 0.5).fract();
+        let u = if u < 0.0 { u + 1.0 } else { u };
+        let v = dir.y.max(-1.0).min(1.0).acos() / PI;
+        (u, v)
+    }
+
+    fn from_uv(&self, u : f32, v : f32) -> Vec3<f32> {
+        let lon = (u - 0.5) * 2.0 * PI + self.rotation;
+        let theta = v * PI;
+        Vec3::new(theta.sin() * lon.sin(), theta.cos(), theta.sin() * lon.cos())
+    }
+
+    fn pixel_index(&self, u : f32, v : f32) -> usize {
+        let x = ((u * self.w as f32) as usize).min(self.w - 1);
+        let y = ((v * self.h as f32) as usize).min(self.h - 1);
+        y * self.w + x
+    }
+
+    /// Radiance coming from the direction.
+    pub fn lookup(&self, dir : Vec3<f32>) -> Vec3<f32> {
+        let (u, v) = self.to_uv(dir);
+        self.pixels[self.pixel_index(u, v)]
+    }
+
+    /// Solid angle density `sample` picks `dir` with.
+    pub fn pdf(&self, dir : Vec3<f32>) -> f32 {
+        let (u, v) = self.to_uv(dir);
+        let sin_theta = (v * PI).sin();
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let y = ((v * self.h as f32) as usize).min(self.h - 1);
+        let x = ((u * self.w as f32) as usize).min(self.w - 1);
+        let row = &self.cols[y * (self.w + 1) .. (y + 1) * (self.w + 1)];
+        let p = (self.rows[y + 1] - self.rows[y]) * (row[x + 1] - row[x]);
+        p * (self.w * self.h) as f32 / (2.0 * PI * PI * sin_theta)
+    }
+
+    /// Direction picked proportionally to the brightness of the map,
+    /// its radiance and its solid angle density.
+    pub fn sample(&self, s : Vec2<f32>) -> (Vec3<f32>, Vec3<f32>, f32) {
+        let (y, dy) = find_interval(&self.rows, s.y);
+        let (x, dx) = find_interval(&self.cols[y * (self.w + 1) .. (y + 1) * (self.w + 1)], s.x);
+        let u = (x as f32 + dx) / self.w as f32;
+        let v = (y as f32 + dy) / self.h as f32;
+        let dir = self.from_uv(u, v);
+        (dir, self.pixels[y * self.w + x], self.pdf(dir))
+    }
+}
+
+#[test]
+fn test_env_map() {
+    // dark map with one bright pixel
+    let (w, h) = (8, 4);
+    let mut pixels = vec![Vec3::new(0.01, 0.01, 0.01); w * h];
+    pixels[1 * w + 5] = Vec3::new(100.0, 100.0, 100.0);
+    let env = EnvMap::new(w, h, pixels, 1.0, 0.0);
+
+    let up = env.from_uv(0.5, 0.0);
+    assert!((up - Vec3::new(0.0, 1.0, 0.0)).norm() < 0.0001);
+    let forward = env.to_uv(Vec3::new(0.0, 0.0, 1.0));
+    assert!((forward.0 - 0.5).abs() < 0.0001 && (forward.1 - 0.5).abs() < 0.0001);
+
+    let mut bright = 0;
+    for i in 0 .. 100 {
+        let s = Vec2::new((i % 10) as f32 / 10.0 + 0.05, (i / 10) as f32 / 10.0 + 0.05);
+        let (dir, radiance, pdf) = env.sample(s);
+        assert_eq!(radiance, env.lookup(dir));
+        assert!(pdf > 0.0);
+        if radiance.x > 1.0 {
+            bright += 1;
+        }
+    }
+    assert!(bright > 90);
+}
+
+#[test]
+fn test_parse_hdr() {
+    let mut bytes = b"#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y 1 +X 8\n".to_vec();
+    // one run length encoded scanline: 8 pixels of (128, 64, 0) with exponent 129
+    bytes.extend_from_slice(&[2, 2, 0, 8, 136, 128, 136, 64, 136, 0, 136, 129]);
+    let (w, h, pixels) = parse_hdr(&bytes).unwrap();
+    assert_eq!((w, h), (8, 1));
+    assert_eq!(pixels[7], Vec3::new(1.0, 0.5, 0.0));
+    assert!(parse_hdr(b"P6\n").is_err());
+}
@@ -0,0 +1,141 @@
+use na::*;
+
+/// Pixel reconstruction filter, weights samples by their offset from the pixel center.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    /// Plain average of the samples inside the pixel.
+    Box,
+    /// Linear falloff over one pixel around the center.
+    Tent,
+    /// Gaussian with alpha 2, cut at 1.5 pixels.
+    Gaussian,
+    /// Mitchell-Netravali with B = C = 1/3 over 2 pixels, sharp with little ringing.
+    Mitchell
+}
+
+fn mitchell_1d(x : f32) -> f32 {
+    let (b, c) = (1.0 / 3.0, 1.0 / 3.0);
+    let x = x.abs();
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x * x * x + (-18.0 + 12.0 * b + 6.0 * c) * x * x + (6.0 - 2.0 * b)) / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x * x * x + (6.0 * b + 30.0 * c) * x * x +
+         (-12.0 * b - 48.0 * c) * x + (8.0 * b + 24.0 * c)) / 6.0
+    } else {
+        0.0
+    }
+}
+
+impl Filter {
+    pub fn from_name(s : &str) -> Option<Filter> {
+        match &s.to_lowercase()[..] {
+            "box"      => Some(Filter::Box),
+            "tent"     => Some(Filter::Tent),
+            "gaussian" => Some(Filter::Gaussian),
+            "mitchell" => Some(Filter::Mitchell),
+            _ => None
+        }
+    }
+
+    /// Samples further than this from a pixel center, in pixels along an axis, don't touch it.
+    pub fn radius(&self) -> f32 {
+        match *self {
+            Filter::Box      => 0.5,
+            Filter::Tent     => 1.0,
+            Filter::Gaussian => 1.5,
+            Filter::Mitchell => 2.0
+        }
+    }
+
+    fn eval_1d(&self, x : f32) -> f32 {
+        let r = self.radius();
+        if x.abs() >= r {
+            return 0.0;
+        }
+        match *self {
+            Filter::Box      => 1.0,
+            Filter::Tent     => r - x.abs(),
+            Filter::Gaussian => (-2.0 * x * x).exp() - (-2.0 * r * r).exp(),
+            Filter::Mitchell => mitchell_1d(x)
+        }
+    }
+
+    pub fn eval(&self, dx : f32, dy : f32) -> f32 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+}
+
+/// Weighted sums of the samples around every pixel, `w * h` pixels.
+pub struct Film {
+    pub w   : i32,
+    pub h   : i32,
+    filter  : Filter,
+    sums    : Vec<Vec3<f32>>,
+    weights : Vec<f32>
+}
+
+impl Film {
+    pub fn new(w : i32, h : i32, filter : Filter) -> Film {
+        Film {
+            w       : w,
+            h       : h,
+            filter  : filter,
+            sums    : vec![Vec3::new(0.0, 0.0, 0.0); (w * h) as usize],
+            weights : vec![0.0; (w * h) as usize]
+        }
+    }
+
+    /// Adds a sample at (x, y) in film pixels, pixel i covers [i, i + 1),
+    /// to every pixel the filter reaches.
+    pub fn add_sample(&mut self, x : f32, y : f32, color : Vec3<f32>) {
+        let r = self.filter.radius();
+        let x0 = ((x - 0.5 - r).ceil() as i32).max(0);
+        let x1 = ((x - 0.5 + r).floor() as i32).min(self.w - 1);
+        let y0 = ((y - 0.5 - r).ceil() as i32).max(0);
+        let y1 = ((y - 0.5 + r).floor() as i32).min(self.h - 1);
+        for py in y0 .. y1 + 1 {
+            for px in x0 .. x1 + 1 {
+                let weight = self.filter.eval(px as f32 + 0.5 - x, py as f32 + 0.5 - y);
+                if weight != 0.0 {
+                    let i = (py * self.w + px) as usize;
+                    self.sums[i] = self.sums[i] + color * weight;
+                    self.weights[i] += weight;
+                }
+            }
+        }
+    }
+
+    /// Adds the samples of another film of the same size, like one rendered by another thread.
+    pub fn merge(&mut self, other : &Film) {
+        for i in 0 .. self.sums.len() {
+            self.sums[i] = self.sums[i] + other.sums[i];
+            self.weights[i] += other.weights[i];
+        }
+    }
+
+    /// Filtered pixel value, black where no sample landed.
+    pub fn pixel(&self, x : i32, y : i32) -> Vec3<f32> {
+        let i = (y * self.w + x) as usize;
+        if self.weights[i] > 0.0 {
+            self.sums[i] / self.weights[i]
+        } else {
+            Vec3::new(0.0, 0.0, 0.0)
+        }
+    }
+}
+
+#[test]
+fn test_film() {
+    let mut film = Film::new(4, 4, Filter::Box);
+    film.add_sample(1.2, 1.7, Vec3::new(1.0, 0.0, 0.0));
+    film.add_sample(1.8, 1.1, Vec3::new(0.0, 1.0, 0.0));
+    assert_eq!(film.pixel(1, 1), Vec3::new(0.5, 0.5, 0.0));
+    assert_eq!(film.pixel(0, 1), Vec3::new(0.0, 0.0, 0.0));
+
+    // wider filters spread a sample over the neighbours, nearest one counting most
+    let mut film = Film::new(4, 4, Filter::Mitchell);
+    film.add_sample(1.5, 1.5, Vec3::new(1.0, 1.0, 1.0));
+    assert!((film.pixel(2, 1) - Vec3::new(1.0, 1.0, 1.0)).norm() < 0.0001);
+    assert!(Filter::Mitchell.eval(0.0, 0.0) > Filter::Mitchell.eval(1.0, 0.0));
+    assert!(Filter::Gaussian.eval(1.6, 0.0) == 0.0 && Filter::Tent.eval(0.5, 0.5) == 0.25);
+}
@@ -0,0 +1,98 @@
+use core::*;
+use scene::*;
+use light::*;
+use sampling::*;
+
+use std::f32::MAX;
+use std::f32::consts::PI;
+
+/// Light carried along a ray, 255 is white.
+pub type Spectrum = Vec3<f32>;
+
+/// Computes the light coming back along a camera ray.
+/// `render` calls `li` for every sample, so new lighting models plug in without touching the loop.
+pub trait Integrator {
+    fn li(&self, scene : &Scene, ray : Ray, sampler : &mut Sampler) -> Spectrum;
+}
+
+/// Light from `light` reflected at `pos` toward the viewer, where `weight(dir)` is
+/// the brdf times the cosine times PI for light coming from `dir` (the albedo for diffuse surfaces).
+/// A point light gives `colour * weight`, divided by the squared distance if the scene has falloff.
+/// Area lights and suns with a diameter are sampled uniformly with `samples` shadow rays
+/// and the visible ones averaged.
+pub fn reflected_light<F : Fn(Vec3<f32>) -> Spectrum>(scene : &Scene, light : &Light, pos : Vec3<f32>,
+                                                     samples : i32, sampler : &mut Sampler, weight : F) -> Spectrum {
+    let samples = if light.is_delta() { 1 } else { samples.max(1) };
+    let sum = (0 .. samples).fold(Vec3::new(0.0, 0.0, 0.0), |acc, _| {
+        let (dir, dist, color) = light.sample_incident(pos, sampler.next_2d());
+        let w = weight(dir);
+        if w.x + w.y + w.z <= 0.0 || scene.objects.occluded(Ray::spawn(pos, dir), dist) {
+            acc
+        } else {
+            let falloff = if scene.falloff && !light.fixed_falloff() { dist * dist } else { 1.0 };
+            acc + color * w / falloff
+        }
+    });
+    sum / samples as f32
+}
+
+/// Environment light reflected at `pos` with `weight` like in `reflected_light`,
+/// from `samples` importance sampled rays. A uniform map of 1.0 and a weight of 1.0 give 1.0.
+pub fn reflected_env<F : Fn(Vec3<f32>) -> Spectrum>(scene : &Scene, pos : Vec3<f32>,
+                                                   samples : i32, sampler : &mut Sampler, weight : F) -> Spectrum {
+    let env = match scene.env {
+        Some(ref env) => env,
+        None => return Vec3::new(0.0, 0.0, 0.0)
+    };
+    let samples = samples.max(1);
+    let sum = (0 .. samples).fold(Vec3::new(0.0, 0.0, 0.0), |acc, _| {
+        let (dir, radiance, pdf) = env.sample(sampler.next_2d());
+        let w = weight(dir);
+        if w.x + w.y + w.z <= 0.0 || pdf <= 0.0 || scene.objects.occluded(Ray::spawn(pos, dir), MAX) {
+            acc
+        } else {
+            acc + radiance * w / pdf
+        }
+    });
+    sum / (samples as f32 * PI)
+}
+
+/// Fraction of the hemisphere above the hit point that is free of geometry closer than `distance`.
+#[derive(Clone, Copy, Debug)]
+pub struct AmbientOcclusion {
+    pub distance : f32,
+    pub samples  : i32
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(&self, scene : &Scene, ray : Ray, sampler : &mut Sampler) -> Spectrum {
+        let intersect = match scene.objects.intersect(ray) {
+            None => return Vec3::new(0.0, 0.0, 0.0),
+            Some((_, intersect)) => intersect
+        };
+        let normal = if ray.dir.dot(&intersect.normal) > 0.0 {
+            intersect.normal * -1.0
+        } else {
+            intersect.normal
+        };
+        let free = (0 .. self.samples).filter(|_| {
+            let u = sampler.next_2d();
+            let dir = to_world(cosine_hemisphere(u.x, u.y), normal);
+            !scene.objects.occluded(Ray::spawn(intersect.pos, dir), self.distance)
+        }).count();
+        Vec3::new(1.0, 1.0, 1.0) * (free as f32 / self.samples as f32 * 255.0)
+    }
+}
+
+/// Debug view: shading normals mapped from [-1, 1] to colours, black on a miss.
+#[derive(Clone, Copy, Debug)]
+pub struct Normals;
+
+impl Integrator for Normals {
+    fn li(&self, scene : &Scene, ray : Ray, _ : &mut Sampler) -> Spectrum {
+        match scene.objects.intersect(ray) {
+            None => Vec3::new(0.0, 0.0, 0.0),
+            Some((_, intersect)) => (intersect.normal + Vec3::new(1.0, 1.0, 1.0)) * 127.5
+        }
+    }
+}
@@ -0,0 +1,31 @@
+extern crate image;
+extern crate rand;
+extern crate nalgebra as na;
+extern crate rustc_serialize;
+
+pub mod core;
+pub mod light;
+pub mod materials;
+pub mod objects;
+pub mod camera;
+pub mod surface;
+pub mod microfacet;
+pub mod bsdf;
+pub mod texture;
+pub mod principled;
+pub mod envmap;
+pub mod emitter;
+pub mod scene;
+pub mod sampling;
+pub mod integrator;
+pub mod whitted;
+pub mod pathtracer;
+pub mod film;
+pub mod render;
+
+#[cfg(test)]
+mod testing;
+
+pub use integrator::*;
+pub use film::*;
+pub use render::*;
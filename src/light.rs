@@ -1,12 +1,200 @@
 use na::*;
 
+use core::ray::*;
+use sampling::*;
+use emitter::*;
+
+use std::f32::MAX;
+use std::f32::consts::PI;
+
 pub enum Light {
-    PointLight(Vec3<f32>, Vec3<f32>)
+    /// Position, colour.
+    PointLight(Vec3<f32>, Vec3<f32>),
+    /// Center, radius, colour. The colour of the area lights is their intensity straight
+    /// in front, like for a point light; the distance always dims them, as emissive objects.
+    SphereLight(Vec3<f32>, f32, Vec3<f32>),
+    /// Center, normal, radius, colour. Shines to both sides.
+    DiskLight(Vec3<f32>, Vec3<f32>, f32, Vec3<f32>),
+    /// Center, the two edges, colour. Shines to both sides.
+    RectLight(Vec3<f32>, Vec3<f32>, Vec3<f32>, Vec3<f32>),
+    /// Position, direction, inner and outer cone half-angles in radians, colour.
+    /// Full colour inside the inner cone, fading smoothly to nothing at the outer one.
+    SpotLight(Vec3<f32>, Vec3<f32>, f32, f32, Vec3<f32>),
+    /// Direction the light travels in, angular diameter in radians, colour.
+    /// Light from infinitely far away, like the sun; a non zero diameter gives soft shadows.
+    DirectionalLight(Vec3<f32>, f32, Vec3<f32>),
+    /// Glowing scene object: its shape and emitted radiance.
+    /// Unlike the other lights it is physically based, the distance always dims it.
+    Emissive(Emitter, Vec3<f32>)
 }
 
+fn smoothstep(lo : f32, hi : f32, x : f32) -> f32 {
+    let t = ((x - lo) / (hi - lo)).max(0.0).min(1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+impl Light {
+    pub fn color(&self) -> Vec3<f32> {
+        match *self {
+            Light::PointLight(_, c) |
+            Light::SphereLight(_, _, c) |
+            Light::DiskLight(_, _, _, c) |
+            Light::RectLight(_, _, _, c) |
+            Light::SpotLight(_, _, _, _, c) |
+            Light::DirectionalLight(_, _, c) |
+            Light::Emissive(_, c) => c
+        }
+    }
+
+    /// Point like lights need a single shadow ray, the area ones are averaged over many.
+    pub fn is_delta(&self) -> bool {
+        match *self {
+            Light::PointLight(..) | Light::SpotLight(..) => true,
+            Light::DirectionalLight(_, diameter, _) => diameter <= 0.0,
+            _ => false
+        }
+    }
+
+    /// Brightness doesn't follow the scene falloff setting: directional lights are
+    /// infinitely far away, area lights and emissive objects account for the distance themselves.
+    pub fn fixed_falloff(&self) -> bool {
+        match *self {
+            Light::DirectionalLight(..) | Light::Emissive(..) | Light::SphereLight(..) |
+            Light::DiskLight(..) | Light::RectLight(..) => true,
+            _ => false
+        }
+    }
+
+    /// Light arriving at `pos`: unit direction toward the light, distance to it
+    /// (MAX for directional lights) and colour, before the cosine and occlusion.
+    pub fn sample_incident(&self, pos : Vec3<f32>, u : Vec2<f32>) -> (Vec3<f32>, f32, Vec3<f32>) {
+        match *self {
+            Light::DirectionalLight(dir, diameter, c) => {
+                let to_light = if diameter > 0.0 {
+                    to_world(uniform_cone(u.x, u.y, (diameter / 2.0).cos()), dir * -1.0)
+                } else {
+                    dir * -1.0
+                };
+                (to_light, MAX, c)
+            }
+            Light::SpotLight(light_pos, dir, inner, outer, c) => {
+                let to_light = light_pos - pos;
+                let dist = to_light.norm();
+                let cos = -(to_light / dist).dot(&dir);
+                (to_light / dist, dist, c * smoothstep(outer.cos(), inner.cos(), cos))
+            }
+            Light::Emissive(ref emitter, radiance) => {
+                let (p, n) = emitter.sample(u);
+                let to_light = p - pos;
+                let dist = to_light.norm();
+                let dir = to_light / dist;
+                // radiance over the solid angle of the sampled patch, both sides glow
+                let cos_l = n.dot(&dir).abs();
+                // stop short of the emitter, or it would shadow itself
+                (dir, dist - ray_epsilon(p), radiance * (emitter.area() * cos_l / (PI * dist * dist)))
+            }
+            Light::PointLight(light_pos, c) => {
+                let to_light = light_pos - pos;
+                let dist = to_light.norm();
+                (to_light / dist, dist, c)
+            }
+            Light::SphereLight(..) | Light::DiskLight(..) | Light::RectLight(..) => {
+                let p = self.sample_point(u);
+                let to_light = p - pos;
+                let dist = to_light.norm();
+                let dir = to_light / dist;
+                // the intensity is spread over the area seen from the front, which is
+                // a quarter of the sphere area and all of the flat ones; only the near
+                // half of a sphere faces `pos`, both sides of the flat lights glow
+                let (cos_l, area) = match *self {
+                    Light::SphereLight(center, _, _) => (-(p - center).normalize().dot(&dir), 4.0),
+                    Light::DiskLight(_, normal, _, _) => (normal.dot(&dir).abs(), 1.0),
+                    Light::RectLight(_, edge_u, edge_v, _) => (edge_u.cross(&edge_v).normalize().dot(&dir).abs(), 1.0),
+                    _ => (1.0, 1.0)
+                };
+                (dir, dist, self.color() * (cos_l.max(0.0) * area / (dist * dist)))
+            }
+        }
+    }
+
+    /// Point on the light, uniformly distributed over its area for `u` uniform in the unit square.
+    /// Directional lights have no position, they give the origin.
+    pub fn sample_point(&self, u : Vec2<f32>) -> Vec3<f32> {
+        match *self {
+            Light::PointLight(pos, _) | Light::SpotLight(pos, _, _, _, _) => pos,
+            Light::DirectionalLight(..) => Vec3::new(0.0, 0.0, 0.0),
+            Light::Emissive(ref emitter, _) => emitter.sample(u).0,
+            Light::SphereLight(pos, radius, _) => pos + uniform_sphere(u.x, u.y) * radius,
+            Light::DiskLight(pos, normal, radius, _) => {
+                let d = sample_disk(u.x, u.y) * radius;
+                pos + to_world(Vec3::new(d.x, d.y, 0.0), normal)
+            }
+            Light::RectLight(pos, edge_u, edge_v, _) =>
+                pos + edge_u * (u.x - 0.5) + edge_v * (u.y - 0.5)
+        }
+    }
+}
 
-#[allow(dead_code)]
 pub fn point_light_white(v : Vec3<f32>) -> Light {
     Light::PointLight(v, Vec3::new(1.0, 1.0, 1.0))
 }
 
+#[test]
+fn test_sample_point() {
+    let us = [Vec2::new(0.0, 0.0), Vec2::new(0.3, 0.8), Vec2::new(0.99, 0.5)];
+    let pos = Vec3::new(1.0, 2.0, 3.0);
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    for &u in &us {
+        let p = Light::SphereLight(pos, 2.0, white).sample_point(u);
+        assert!(((p - pos).norm() - 2.0).abs() < 0.0001);
+
+        let normal = Vec3::new(0.0, 1.0, 0.0);
+        let p = Light::DiskLight(pos, normal, 2.0, white).sample_point(u);
+        assert!((p - pos).dot(&normal).abs() < 0.0001 && (p - pos).norm() <= 2.0001);
+
+        let p = Light::RectLight(pos, Vec3::new(4.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 2.0), white).sample_point(u);
+        assert!((p.x - pos.x).abs() <= 2.0 && (p.z - pos.z).abs() <= 1.0 && p.y == pos.y);
+    }
+    assert!(Light::PointLight(pos, white).is_delta());
+}
+
+#[test]
+fn test_spot_and_sun() {
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let down = Vec3::new(0.0, -1.0, 0.0);
+    let u = Vec2::new(0.5, 0.5);
+    let spot = Light::SpotLight(Vec3::new(0.0, 10.0, 0.0), down, 0.2, 0.4, white);
+    let (dir, dist, c) = spot.sample_incident(Vec3::new(0.0, 0.0, 0.0), u);
+    assert_eq!((dir, dist, c), (Vec3::new(0.0, 1.0, 0.0), 10.0, white));
+    // 45 degrees off the axis is outside the outer cone
+    assert_eq!(spot.sample_incident(Vec3::new(10.0, 0.0, 0.0), u).2, Vec3::new(0.0, 0.0, 0.0));
+    let (_, _, c) = spot.sample_incident(Vec3::new(10.0 * (0.3f32).tan(), 0.0, 0.0), u);
+    assert!(c.x > 0.0 && c.x < 1.0);
+
+    let sun = Light::DirectionalLight(down, 0.0, white);
+    assert_eq!(sun.sample_incident(Vec3::new(5.0, 0.0, 0.0), u), (Vec3::new(0.0, 1.0, 0.0), MAX, white));
+    let (dir, _, _) = Light::DirectionalLight(down, 0.1, white).sample_incident(Vec3::new(5.0, 0.0, 0.0), u);
+    assert!(dir.y >= (0.05f32).cos() - 0.0001);
+}
+
+#[test]
+fn test_area_light_intensity() {
+    // seen from afar, area lights are as bright as a point light of the same colour with falloff
+    let white = Vec3::new(1.0, 1.0, 1.0);
+    let center = Vec3::new(0.0, 10.0, 0.0);
+    let lights = [Light::SphereLight(center, 0.5, white),
+                  Light::DiskLight(center, Vec3::new(0.0, 1.0, 0.0), 0.5, white),
+                  Light::RectLight(center, Vec3::new(0.5, 0.0, 0.0), Vec3::new(0.0, 0.0, 0.5), white)];
+    for light in &lights {
+        assert!(light.fixed_falloff());
+        let n = 64;
+        let sum = (0 .. n * n).fold(0.0, |acc, i| {
+            let u = Vec2::new(((i % n) as f32 + 0.5) / n as f32, ((i / n) as f32 + 0.5) / n as f32);
+            acc + light.sample_incident(Vec3::new(0.0, 0.0, 0.0), u).2.x
+        }) / (n * n) as f32;
+        assert!((sum - 0.01).abs() < 0.0005, "{}", sum);
+    }
+    // the edge of a flat light gives nothing
+    let (_, _, c) = lights[1].sample_incident(Vec3::new(10.0, 10.0, 0.0), Vec2::new(0.5, 0.5));
+    assert!(c.x < 1e-6);
+}
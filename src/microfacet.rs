@@ -0,0 +1,181 @@
+use na::*;
+
+use core::*;
+use surface::*;
+use sampling::*;
+use integrator::*;
+use bsdf::*;
+
+use std::f32::consts::PI;
+
+/// GGX (Trowbridge-Reitz) distribution of microfacet normals, `cos` is between the normal and `h`.
+pub fn ggx_d(cos : f32, alpha : f32) -> f32 {
+    if cos <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let d = cos * cos * (a2 - 1.0) + 1.0;
+    a2 / (PI * d * d)
+}
+
+/// Smith masking of one direction for GGX.
+pub fn smith_g1(cos : f32, alpha : f32) -> f32 {
+    if cos <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    2.0 * cos / (cos + (a2 + (1.0 - a2) * cos * cos).sqrt())
+}
+
+/// Half vector in the local frame around +z, with density `ggx_d(cos) * cos`.
+pub fn sample_ggx(u : f32, v : f32, alpha : f32) -> Vec3<f32> {
+    let tan2 = alpha * alpha * u / (1.0 - u).max(1e-7);
+    let cos = 1.0 / (1.0 + tan2).sqrt();
+    let sin = (1.0 - cos * cos).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(sin * phi.cos(), sin * phi.sin(), cos)
+}
+
+/// Solid angle density of reflecting `wo` to `wi` about a half vector picked by `sample_ggx`.
+pub fn ggx_pdf(wo : Vec3<f32>, wi : Vec3<f32>, alpha : f32) -> f32 {
+    let h = (wo + wi).normalize();
+    ggx_d(h.z, alpha) * h.z / (4.0 * wo.dot(&h).abs().max(1e-7))
+}
+
+/// Reflectance of a metal with the complex refraction index `eta + i k`, per colour channel.
+pub fn fresnel_conductor(cos : f32, eta : Vec3<f32>, k : Vec3<f32>) -> Vec3<f32> {
+    let c2 = cos * cos;
+    let channel = |eta : f32, k : f32| {
+        let t = eta * eta + k * k;
+        let rs = (t - 2.0 * eta * cos + c2) / (t + 2.0 * eta * cos + c2);
+        let rp = (t * c2 - 2.0 * eta * cos + 1.0) / (t * c2 + 2.0 * eta * cos + 1.0);
+        (rs + rp) / 2.0
+    };
+    Vec3::new(channel(eta.x, k.x), channel(eta.y, k.y), channel(eta.z, k.z))
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Coating {
+    Conductor(Vec3<f32>, Vec3<f32>),
+    /// Refraction index of the coat, colour of the diffuse base.
+    Plastic(f32, Vec3<f32>)
+}
+
+/// Rough reflection at one hit point: GGX specular lobe over either a metal
+/// or a diffuse base, in the local shading frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Glossy {
+    alpha   : f32,
+    coating : Coating
+}
+
+impl Glossy {
+    /// Glossy model of `surface`, None for other surfaces.
+    pub fn new(surface : &Surface) -> Option<Glossy> {
+        // perceptually linear roughness, kept off zero so the lobe stays finite
+        let alpha = |roughness : f32| (roughness * roughness).max(1e-3);
+        match *surface {
+            Surface::Conductor { roughness, eta, k } => Some(Glossy {
+                alpha   : alpha(roughness),
+                coating : Coating::Conductor(eta, k)
+            }),
+            Surface::Plastic { roughness, ior, color } => Some(Glossy {
+                alpha   : alpha(roughness),
+                coating : Coating::Plastic(ior, color)
+            }),
+            _ => None
+        }
+    }
+
+    /// Chance to sample the specular lobe rather than the diffuse one.
+    fn specular_weight(&self) -> f32 {
+        match self.coating {
+            Coating::Conductor(..) => 1.0,
+            Coating::Plastic(..) => 0.5
+        }
+    }
+}
+
+impl Bsdf for Glossy {
+    fn eval(&self, wo : Vec3<f32>, wi : Vec3<f32>) -> Spectrum {
+        let (cos_o, cos_i) = (wo.z, wi.z);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let h = (wo + wi).normalize();
+        let cos_h = wo.dot(&h);
+        let spec = ggx_d(h.z, self.alpha) * smith_g1(cos_o, self.alpha) *
+            smith_g1(cos_i, self.alpha) / (4.0 * cos_o);
+        match self.coating {
+            Coating::Conductor(eta, k) => fresnel_conductor(cos_h, eta, k) * spec,
+            Coating::Plastic(ior, albedo) => {
+                // light reaching the base passes the coat twice
+                let f = |cos : f32| fresnel_dielectric(cos, 1.0, ior);
+                let diffuse = albedo * ((1.0 - f(cos_i)) * (1.0 - f(cos_o)) * cos_i / PI);
+                Vec3::new(1.0, 1.0, 1.0) * (f(cos_h) * spec) + diffuse
+            }
+        }
+    }
+
+    fn pdf(&self, wo : Vec3<f32>, wi : Vec3<f32>) -> f32 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let p = self.specular_weight();
+        p * ggx_pdf(wo, wi, self.alpha) + (1.0 - p) * wi.z / PI
+    }
+
+    fn sample(&self, wo : Vec3<f32>, u : Vec3<f32>) -> Option<BsdfSample> {
+        let wi = if u.z < self.specular_weight() {
+            let h = sample_ggx(u.x, u.y, self.alpha);
+            h * (2.0 * wo.dot(&h)) - wo
+        } else {
+            cosine_hemisphere(u.x, u.y)
+        };
+        let pdf = self.pdf(wo, wi);
+        if wi.z <= 0.0 || pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            wi       : wi,
+            weight   : self.eval(wo, wi) / pdf,
+            pdf      : pdf,
+            specular : false
+        })
+    }
+
+    fn is_glossy(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_ggx() {
+    // the distribution integrates to one over the projected hemisphere
+    let alpha = 0.3;
+    let n = 2000;
+    let sum = (0 .. n).fold(0.0, |acc, i| {
+        let cos = (i as f32 + 0.5) / n as f32;
+        acc + ggx_d(cos, alpha) * cos * 2.0 * PI / n as f32
+    });
+    assert!((sum - 1.0).abs() < 0.01);
+    assert!(smith_g1(1.0, alpha) > 0.999 && smith_g1(0.1, alpha) < smith_g1(0.5, alpha));
+    assert!((sample_ggx(0.3, 0.6, alpha).norm() - 1.0).abs() < 0.0001);
+
+    let gold = gold();
+    if let Surface::Conductor { eta, k, .. } = gold {
+        let f = fresnel_conductor(1.0, eta, k);
+        assert!(f.x > f.z && f.x > 0.8);
+        assert!(fresnel_conductor(0.01, eta, k).z > 0.9);
+    }
+
+    let glossy = Glossy::new(&copper()).unwrap();
+    let wo = Vec3::new(1.0, 0.0, 1.0).normalize();
+    let mirror = Vec3::new(-1.0, 0.0, 1.0).normalize();
+    assert!(glossy.eval(wo, mirror).x > glossy.eval(wo, Vec3::new(0.0, 0.0, 1.0)).x);
+    assert!(glossy.pdf(wo, mirror) > 0.0);
+    assert_eq!(glossy.eval(wo, Vec3::new(0.0, 0.0, -1.0)), Vec3::new(0.0, 0.0, 0.0));
+    let sample = glossy.sample(wo, Vec3::new(0.5, 0.5, 0.5)).unwrap();
+    assert!(sample.wi.z > 0.0 && !sample.specular);
+    assert!((sample.pdf - glossy.pdf(wo, sample.wi)).abs() < 0.0001);
+}
@@ -0,0 +1,209 @@
+use std::str::FromStr;
+
+use rays::*;
+
+pub const USAGE : &'static str = "Usage: rays [options] <scene.json>
+
+Options:
+    -s, --size WxH         image resolution (default 800x600)
+    -t, --threads N        number of worker threads (default 4)
+    -n, --samples N        samples per pixel (default 1)
+    -o, --output PATH      output image (default res.png)
+    -f, --format FORMAT    png, jpeg or ppm (default: from output extension)
+    -r, --region X,Y,W,H   render only this part of the image
+    -i, --integrator NAME  whitted, path, ao or normals (default whitted)
+    -d, --max-depth N      path length for the path integrator (default 8)
+    -l, --light-samples N  shadow rays per area light (default 16)
+    -e, --exposure EV      brighten by EV stops, negative to darken (default 0)
+    -F, --filter NAME      box, tent, gaussian or mitchell (default box)
+    -h, --help             print this message";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub scene         : String,
+    pub w             : i32,
+    pub h             : i32,
+    pub threads       : i32,
+    pub samples       : i32,
+    pub output        : String,
+    pub format        : Format,
+    pub region        : Region,
+    pub integrator    : IntegratorKind,
+    pub light_samples : i32,
+    pub exposure      : f32,
+    pub filter        : Filter
+}
+
+impl Options {
+    pub fn settings(&self) -> RenderSettings {
+        RenderSettings {
+            threads       : self.threads,
+            samples       : self.samples,
+            region        : Some(self.region),
+            integrator    : self.integrator,
+            light_samples : self.light_samples,
+            exposure      : self.exposure,
+            filter        : self.filter
+        }
+    }
+}
+
+fn parse_list<T : FromStr>(s : &str, sep : char, count : usize, opt : &str) -> Result<Vec<T>, String> {
+    let items = s.split(sep)
+        .map(|x| x.trim().parse::<T>())
+        .collect::<Result<Vec<T>, _>>();
+    match items {
+        Ok(items) => if items.len() == count {
+            Ok(items)
+        } else {
+            Err(format!("{} expects {} values, got `{}`", opt, count, s))
+        },
+        Err(_) => Err(format!("invalid value `{}` for {}", s, opt))
+    }
+}
+
+fn parse_positive(s : &str, opt : &str) -> Result<i32, String> {
+    match s.parse::<i32>() {
+        Ok(x) if x > 0 => Ok(x),
+        _ => Err(format!("{} expects a positive number, got `{}`", opt, s))
+    }
+}
+
+/// Parses command line arguments, without the program name.
+/// Returns `Ok(None)` if only help was requested.
+pub fn parse_args(args : &[String]) -> Result<Option<Options>, String> {
+    let mut scene = None;
+    let mut size = (800, 600);
+    let mut threads = 4;
+    let mut samples = 1;
+    let mut output = "res.png".to_string();
+    let mut format = None;
+    let mut region = None;
+    let mut integrator = "whitted".to_string();
+    let mut max_depth = 8;
+    let mut light_samples = 16;
+    let mut exposure = 0.0;
+    let mut filter = Filter::Box;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let opt = &arg[..];
+        if opt == "-h" || opt == "--help" {
+            return Ok(None);
+        }
+        if !opt.starts_with("-") {
+            if scene.is_some() {
+                return Err(format!("unexpected argument `{}`", opt));
+            }
+            scene = Some(arg.clone());
+            continue;
+        }
+        let value = match args.next() {
+            Some(value) => &value[..],
+            None => return Err(format!("{} expects a value", opt))
+        };
+        match opt {
+            "-s" | "--size" => {
+                let s = try!(parse_list::<i32>(value, 'x', 2, opt));
+                if s[0] <= 0 || s[1] <= 0 {
+                    return Err(format!("invalid value `{}` for {}", value, opt));
+                }
+                size = (s[0], s[1]);
+            }
+            "-t" | "--threads" => threads = try!(parse_positive(value, opt)),
+            "-n" | "--samples" => samples = try!(parse_positive(value, opt)),
+            "-o" | "--output"  => output = value.to_string(),
+            "-f" | "--format"  => match Format::from_name(value) {
+                Some(f) => format = Some(f),
+                None => return Err(format!("unknown format `{}`", value))
+            },
+            "-r" | "--region" => {
+                let r = try!(parse_list::<i32>(value, ',', 4, opt));
+                region = Some(Region { x : r[0], y : r[1], w : r[2], h : r[3] });
+            }
+            "-i" | "--integrator" => integrator = value.to_string(),
+            "-d" | "--max-depth"  => max_depth = try!(parse_positive(value, opt)),
+            "-l" | "--light-samples" => light_samples = try!(parse_positive(value, opt)),
+            "-e" | "--exposure" => match value.parse::<f32>() {
+                Ok(ev) => exposure = ev,
+                Err(_) => return Err(format!("{} expects a number, got `{}`", opt, value))
+            },
+            "-F" | "--filter" => match Filter::from_name(value) {
+                Some(f) => filter = f,
+                None => return Err(format!("unknown filter `{}`", value))
+            },
+            _ => return Err(format!("unknown option `{}`", opt))
+        }
+    }
+
+    let scene = match scene {
+        Some(scene) => scene,
+        None => return Err("no scene file given".to_string())
+    };
+    let (w, h) = size;
+    let region = region.unwrap_or(Region { x : 0, y : 0, w : w, h : h });
+    if region.x < 0 || region.y < 0 || region.w <= 0 || region.h <= 0 ||
+        region.x + region.w > w || region.y + region.h > h {
+        return Err(format!("region {},{},{},{} is outside of the {}x{} image",
+                           region.x, region.y, region.w, region.h, w, h));
+    }
+    let format = match format {
+        Some(f) => f,
+        None => output.rsplit('.').next().and_then(Format::from_name).unwrap_or(Format::Png)
+    };
+
+    let integrator = match &integrator[..] {
+        "whitted" => IntegratorKind::Whitted,
+        "path"    => IntegratorKind::Path(max_depth),
+        "ao"      => IntegratorKind::AmbientOcclusion(1.0),
+        "normals" => IntegratorKind::Normals,
+        other => return Err(format!("unknown integrator `{}`", other))
+    };
+
+    Ok(Some(Options {
+        scene         : scene,
+        w             : w,
+        h             : h,
+        threads       : threads,
+        samples       : samples,
+        output        : output,
+        format        : format,
+        region        : region,
+        integrator    : integrator,
+        light_samples : light_samples,
+        exposure      : exposure,
+        filter        : filter
+    }))
+}
+
+#[test]
+fn test_parse_args() {
+    let args = |s : &str| s.split(' ').map(|x| x.to_string()).collect::<Vec<String>>();
+
+    let opts = parse_args(&args("scenes/room.json")).unwrap().unwrap();
+    assert_eq!(opts.scene, "scenes/room.json");
+    assert_eq!((opts.w, opts.h, opts.threads, opts.samples), (800, 600, 4, 1));
+    assert_eq!(opts.format, Format::Png);
+    assert_eq!(opts.region, Region { x : 0, y : 0, w : 800, h : 600 });
+
+    let opts = parse_args(&args("-s 400x300 -t 8 -n 16 -o out.ppm -r 10,20,100,50 a.json"))
+        .unwrap().unwrap();
+    assert_eq!((opts.w, opts.h, opts.threads, opts.samples), (400, 300, 8, 16));
+    assert_eq!(opts.output, "out.ppm");
+    assert_eq!(opts.format, Format::Ppm);
+    assert_eq!(opts.region, Region { x : 10, y : 20, w : 100, h : 50 });
+    assert_eq!(opts.integrator, IntegratorKind::Whitted);
+
+    let opts = parse_args(&args("-i path -d 5 a.json")).unwrap().unwrap();
+    assert_eq!(opts.integrator, IntegratorKind::Path(5));
+    assert_eq!(opts.light_samples, 16);
+    assert_eq!(parse_args(&args("-e -1.5 a.json")).unwrap().unwrap().exposure, -1.5);
+    assert_eq!(parse_args(&args("-F mitchell a.json")).unwrap().unwrap().filter, Filter::Mitchell);
+    assert_eq!(parse_args(&args("-l 4 a.json")).unwrap().unwrap().settings().light_samples, 4);
+    assert!(parse_args(&args("-i foo a.json")).is_err());
+
+    assert_eq!(parse_args(&args("a.json --help")).unwrap(), None);
+    assert!(parse_args(&args("-t 0 a.json")).is_err());
+    assert!(parse_args(&args("-r 390,0,20,20 -s 400x300 a.json")).is_err());
+    assert!(parse_args(&args("-s 400x300")).is_err());
+}
@@ -0,0 +1,137 @@
+use core::*;
+use scene::*;
+use light::*;
+use emitter::*;
+use surface::*;
+use sampling::*;
+use integrator::*;
+use bsdf::*;
+
+use std::f32::MAX;
+use std::f32::consts::PI;
+
+/// Bounces before Russian roulette may start terminating paths.
+const MIN_DEPTH : i32 = 3;
+
+/// Direct light from all lights, one shadow ray each. `eval(dir)` is the brdf times the cosine.
+/// Lights are as bright as in `color`: a point light gives `colour * cos` to a white diffuse surface.
+/// Glowing objects can be hit by bounces too, so they are weighted against bounces sampled by `pdf`.
+fn direct_light(scene : &Scene, pos : Vec3<f32>, eval : &Fn(Vec3<f32>) -> Spectrum, pdf : &Fn(Vec3<f32>) -> f32,
+                sampler : &mut Sampler) -> Spectrum {
+    scene.lights.iter().fold(Vec3::new(0.0, 0.0, 0.0), |acc, l| match *l {
+        Light::Emissive(ref emitter, emitted) => acc + direct_emitter(scene, emitter, emitted, pos, eval, pdf, sampler),
+        _ => acc + reflected_light(scene, l, pos, 1, sampler, |dir| eval(dir) * PI)
+    })
+}
+
+/// Light from a glowing object through one point sampled on it by area, like `direct_env`.
+fn direct_emitter(scene : &Scene, emitter : &Emitter, emitted : Spectrum, pos : Vec3<f32>,
+                  eval : &Fn(Vec3<f32>) -> Spectrum, pdf : &Fn(Vec3<f32>) -> f32,
+                  sampler : &mut Sampler) -> Spectrum {
+    let (p, n) = emitter.sample(sampler.next_2d());
+    let to_light = p - pos;
+    let dist = to_light.norm();
+    let dir = to_light / dist;
+    let light_pdf = solid_angle_pdf(emitter.area(), pos, p, n);
+    let f = eval(dir);
+    if f.x + f.y + f.z <= 0.0 || scene.objects.occluded(Ray::spawn(pos, dir), dist - ray_epsilon(p)) {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    f * emitted * (power_heuristic(light_pdf, pdf(dir)) / light_pdf)
+}
+
+/// Environment light from one importance sample of the map, weighted against
+/// hitting the map with a bounce sampled by `pdf`.
+fn direct_env(scene : &Scene, pos : Vec3<f32>, eval : &Fn(Vec3<f32>) -> Spectrum, pdf : &Fn(Vec3<f32>) -> f32,
+              sampler : &mut Sampler) -> Spectrum {
+    let env = match scene.env {
+        Some(ref env) => env,
+        None => return Vec3::new(0.0, 0.0, 0.0)
+    };
+    let (dir, radiance, env_pdf) = env.sample(sampler.next_2d());
+    let f = eval(dir);
+    if f.x + f.y + f.z <= 0.0 || env_pdf <= 0.0 || scene.objects.occluded(Ray::spawn(pos, dir), MAX) {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    f * radiance * (power_heuristic(env_pdf, pdf(dir)) / env_pdf)
+}
+
+/// Monte Carlo path tracing: bounces sampled by the BSDF, next event estimation
+/// toward the lights at every non-specular vertex and Russian roulette after `MIN_DEPTH`.
+/// The environment map and glowing objects are both sampled directly and hit by bounces,
+/// combined with multiple importance sampling.
+/// Same scale as `shade`: 255 is white.
+pub fn trace_path(scene : &Scene, ray : Ray, max_depth : i32, sampler : &mut Sampler) -> Spectrum {
+    let mut ray = ray;
+    let mut radiance = Vec3::new(0.0, 0.0, 0.0);
+    let mut throughput = Vec3::new(1.0, 1.0, 1.0);
+    // density of the last bounce if it was a non-specular one, None for camera and specular rays
+    let mut bounce_pdf = None;
+
+    for depth in 0 .. max_depth {
+        let hit = match scene.objects.intersect_surface(ray) {
+            None => {
+                if let Some(ref env) = scene.env {
+                    let weight = bounce_pdf.map_or(1.0, |pdf| power_heuristic(pdf, env.pdf(ray.dir)));
+                    radiance = radiance + throughput * env.lookup(ray.dir) * weight;
+                }
+                break;
+            }
+            Some(hit) => hit
+        };
+        let intersect = &hit.intersect;
+
+        let (frame, bsdf) = match surface_bsdf(&ray, &hit) {
+            Some(bsdf) => bsdf,
+            None => {
+                // after a non-specular bounce this light was sampled directly as well
+                if let Some(&Surface::Emissive { radiance : emitted, area }) = hit.surface {
+                    let weight = bounce_pdf.map_or(1.0, |pdf| {
+                        power_heuristic(pdf, solid_angle_pdf(area, ray.pos, intersect.pos, intersect.normal))
+                    });
+                    radiance = radiance + throughput * emitted * weight;
+                }
+                break;
+            }
+        };
+        let wo = frame.to_local(ray.dir * -1.0);
+
+        if !bsdf.is_specular() {
+            let eval = |dir : Vec3<f32>| bsdf.eval(wo, frame.to_local(dir));
+            let pdf = |dir : Vec3<f32>| bsdf.pdf(wo, frame.to_local(dir));
+            radiance = radiance + throughput * (direct_light(scene, intersect.pos, &eval, &pdf, sampler) +
+                                                direct_env(scene, intersect.pos, &eval, &pdf, sampler));
+        }
+
+        let u = sampler.next_2d();
+        let sample = match bsdf.sample(wo, Vec3::new(u.x, u.y, sampler.next_1d())) {
+            Some(sample) => sample,
+            None => break
+        };
+        throughput = throughput * sample.weight;
+        bounce_pdf = if sample.specular { None } else { Some(sample.pdf) };
+        let dir = frame.to_world(sample.wi);
+        ray = if sample.specular { ray.bounce(intersect.pos, frame.n, dir) } else { Ray::spawn(intersect.pos, dir) };
+
+        if depth >= MIN_DEPTH {
+            let p = throughput.x.max(throughput.y).max(throughput.z).min(0.95);
+            if sampler.next_1d() >= p {
+                break;
+            }
+            throughput = throughput / p;
+        }
+    }
+    radiance * 255.0
+}
+
+/// `trace_path` as an integrator.
+#[derive(Clone, Copy, Debug)]
+pub struct PathTracer {
+    pub max_depth : i32
+}
+
+impl Integrator for PathTracer {
+    fn li(&self, scene : &Scene, ray : Ray, sampler : &mut Sampler) -> Spectrum {
+        trace_path(scene, ray, self.max_depth, sampler)
+    }
+}
@@ -0,0 +1,226 @@
+use na::*;
+
+use texture::*;
+use sampling::*;
+use integrator::*;
+use microfacet::*;
+use bsdf::*;
+
+use std::f32::consts::PI;
+
+/// Fixed roughness of the clear coat, it is always close to a mirror.
+const CLEARCOAT_ALPHA : f32 = 0.05;
+
+/// Principled ("Disney") material with the parameters content tools export,
+/// every one of them constant or read from a texture. All but `color` are in [0, 1].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Principled {
+    pub color        : Mapped,
+    pub metallic     : Mapped,
+    pub roughness    : Mapped,
+    /// Reflectance of the non-metal part, 0.5 is the usual 4%.
+    pub specular     : Mapped,
+    /// Extra reflection at grazing angles, for cloth.
+    pub sheen        : Mapped,
+    /// Second, smooth specular layer on top.
+    pub clearcoat    : Mapped,
+    /// Part of the non-metal that is glass rather than diffuse.
+    pub transmission : Mapped,
+    /// Refraction index of the transmitting part.
+    pub ior          : f32
+}
+
+impl Principled {
+    /// Whether any parameter needs the pixel footprint for its lookups.
+    pub fn needs_footprint(&self) -> bool {
+        [&self.color, &self.metallic, &self.roughness, &self.specular,
+         &self.sheen, &self.clearcoat, &self.transmission].iter().any(|m| m.needs_footprint())
+    }
+
+    /// Parameters at the texture coordinates of a hit, `width` is the pixel footprint in uv.
+    pub fn at(&self, uv : Vec2<f32>, width : f32) -> PrincipledBsdf {
+        let scalar = |m : &Mapped| m.at(uv, width).x.max(0.0).min(1.0);
+        PrincipledBsdf {
+            base         : self.color.at(uv, width),
+            metallic     : scalar(&self.metallic),
+            roughness    : scalar(&self.roughness),
+            specular     : scalar(&self.specular),
+            sheen        : scalar(&self.sheen),
+            clearcoat    : scalar(&self.clearcoat),
+            transmission : scalar(&self.transmission),
+            ior          : self.ior
+        }
+    }
+}
+
+fn pow5(x : f32) -> f32 {
+    let x2 = x * x;
+    x2 * x2 * x
+}
+
+/// Principled material at one hit: Burley diffuse and sheen, a GGX specular lobe
+/// going from dielectric to metal, a clear coat and smooth glass for transmission.
+/// `wo.z < 0` means the ray is inside a transmitting object, where only the glass is left.
+#[derive(Clone, Copy, Debug)]
+pub struct PrincipledBsdf {
+    pub base         : Vec3<f32>,
+    pub metallic     : f32,
+    pub roughness    : f32,
+    pub specular     : f32,
+    pub sheen        : f32,
+    pub clearcoat    : f32,
+    pub transmission : f32,
+    pub ior          : f32
+}
+
+impl PrincipledBsdf {
+    fn alpha(&self) -> f32 {
+        (self.roughness * self.roughness).max(1e-3)
+    }
+
+    fn glass(&self) -> DielectricBsdf {
+        DielectricBsdf { ior : self.ior, tint : self.base }
+    }
+
+    /// Part of the light that meets the glass instead of the opaque lobes.
+    fn glass_weight(&self) -> f32 {
+        (1.0 - self.metallic) * self.transmission
+    }
+
+    /// Chances to sample the glass, diffuse, specular and clear coat lobes.
+    fn lobe_weights(&self) -> (f32, f32, f32, f32) {
+        let glass = self.glass_weight();
+        let diffuse = 0.5 * (1.0 - self.metallic) * (1.0 - self.transmission);
+        let specular = 1.0 - glass - diffuse;
+        let clearcoat = 0.25 * self.clearcoat;
+        let sum = 1.0 + clearcoat;
+        (glass / sum, diffuse / sum, specular / sum, clearcoat / sum)
+    }
+}
+
+impl Bsdf for PrincipledBsdf {
+    fn eval(&self, wo : Vec3<f32>, wi : Vec3<f32>) -> Spectrum {
+        let (cos_o, cos_i) = (wo.z, wi.z);
+        if cos_o <= 0.0 || cos_i <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let white = Vec3::new(1.0, 1.0, 1.0);
+        let h = (wo + wi).normalize();
+        let cos_d = wi.dot(&h);
+        let grazing = pow5(1.0 - cos_d);
+
+        // retro-reflection of rough surfaces brightens the diffuse at grazing angles
+        let fd90 = 0.5 + 2.0 * self.roughness * cos_d * cos_d;
+        let fd = (1.0 + (fd90 - 1.0) * pow5(1.0 - cos_i)) * (1.0 + (fd90 - 1.0) * pow5(1.0 - cos_o));
+        let diffuse = (self.base * (fd / PI) + white * (self.sheen * grazing)) *
+            ((1.0 - self.metallic) * (1.0 - self.transmission));
+
+        let alpha = self.alpha();
+        let f0 = white * (0.08 * self.specular * (1.0 - self.metallic)) + self.base * self.metallic;
+        let spec = (f0 + (white - f0) * grazing) *
+            (ggx_d(h.z, alpha) * smith_g1(cos_o, alpha) * smith_g1(cos_i, alpha) / (4.0 * cos_o * cos_i) *
+             (1.0 - self.glass_weight()));
+
+        let coat = 0.25 * self.clearcoat * (0.04 + 0.96 * grazing) * ggx_d(h.z, CLEARCOAT_ALPHA) *
+            smith_g1(cos_o, CLEARCOAT_ALPHA) * smith_g1(cos_i, CLEARCOAT_ALPHA) / (4.0 * cos_o * cos_i);
+
+        (diffuse + spec + white * coat) * cos_i
+    }
+
+    fn pdf(&self, wo : Vec3<f32>, wi : Vec3<f32>) -> f32 {
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let (_, diffuse, specular, clearcoat) = self.lobe_weights();
+        diffuse * wi.z / PI + specular * ggx_pdf(wo, wi, self.alpha()) +
+            clearcoat * ggx_pdf(wo, wi, CLEARCOAT_ALPHA)
+    }
+
+    fn sample(&self, wo : Vec3<f32>, u : Vec3<f32>) -> Option<BsdfSample> {
+        if wo.z <= 0.0 {
+            return self.glass().sample(wo, u);
+        }
+        let (glass, diffuse, specular, _) = self.lobe_weights();
+        if u.z < glass {
+            let scale = self.glass_weight() / glass;
+            return self.glass().sample(wo, Vec3::new(u.x, u.y, u.z / glass)).map(|sample| BsdfSample {
+                weight : sample.weight * scale,
+                pdf    : sample.pdf * glass,
+                ..sample
+            });
+        }
+        let wi = if u.z < glass + diffuse {
+            cosine_hemisphere(u.x, u.y)
+        } else {
+            let alpha = if u.z < glass + diffuse + specular { self.alpha() } else { CLEARCOAT_ALPHA };
+            let h = sample_ggx(u.x, u.y, alpha);
+            h * (2.0 * wo.dot(&h)) - wo
+        };
+        let pdf = self.pdf(wo, wi);
+        if wi.z <= 0.0 || pdf <= 0.0 {
+            return None;
+        }
+        Some(BsdfSample {
+            wi       : wi,
+            weight   : self.eval(wo, wi) / pdf,
+            pdf      : pdf,
+            specular : false
+        })
+    }
+
+    fn specular(&self, wo : Vec3<f32>) -> Vec<(Vec3<f32>, Spectrum)> {
+        if wo.z <= 0.0 {
+            return self.glass().specular(wo);
+        }
+        let glass = self.glass_weight();
+        if glass <= 0.0 {
+            return Vec::new();
+        }
+        self.glass().specular(wo).into_iter().map(|(wi, weight)| (wi, weight * glass)).collect()
+    }
+
+    fn is_glossy(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn test_principled() {
+    let gray = |x : f32| Mapped::Value(Vec3::new(x, x, x));
+    let material = Principled {
+        color        : Mapped::Value(Vec3::new(0.8, 0.2, 0.2)),
+        metallic     : gray(0.0),
+        roughness    : gray(0.5),
+        specular     : gray(0.5),
+        sheen        : gray(0.0),
+        clearcoat    : gray(1.0),
+        transmission : gray(0.0),
+        ior          : 1.5
+    };
+    let bsdf = material.at(Vec2::new(0.3, 0.3), 0.0);
+    let wo = Vec3::new(0.6, 0.0, 0.8);
+
+    // reflects no more than it gets, estimated by sampling the bsdf itself
+    let mut sampler = Sampler::new(7);
+    let n = 4000;
+    let sum = (0 .. n).fold(Vec3::new(0.0, 0.0, 0.0), |acc, _| {
+        let u = sampler.next_2d();
+        match bsdf.sample(wo, Vec3::new(u.x, u.y, sampler.next_1d())) {
+            Some(sample) => {
+                assert!(!sample.specular && sample.wi.z > 0.0);
+                assert!((sample.pdf - bsdf.pdf(wo, sample.wi)).abs() < 0.001 * sample.pdf.max(1.0));
+                acc + sample.weight
+            }
+            None => acc
+        }
+    }) / n as f32;
+    assert!(sum.x < 1.05 && sum.x > sum.y);
+    assert!(bsdf.specular(wo).is_empty());
+
+    // glass lets light through and keeps it inside
+    let glass = PrincipledBsdf { transmission : 1.0, clearcoat : 0.0, ..bsdf };
+    let lobes = glass.specular(wo);
+    assert_eq!(lobes.len(), 2);
+    assert!(lobes[1].0.z < 0.0);
+    assert!(glass.sample(Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.5, 0.5, 0.5)).unwrap().specular);
+}
@@ -0,0 +1,244 @@
+use core::*;
+
+use image::*;
+use scene::*;
+use sampling::*;
+use film::*;
+use integrator::*;
+use whitted::*;
+use pathtracer::*;
+
+use std::cmp::min;
+use std::fs::File;
+use std::io::Write;
+use std::sync::Mutex;
+use std::thread;
+
+const CHUNK_SIZE : i32 = 5000;
+/// Samples per pixel below which depth of field comes out noisy, every one
+/// goes through another point of the lens.
+pub const LENS_SAMPLES : i32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    Jpeg,
+    Ppm
+}
+
+impl Format {
+    /// Format by its name or file extension.
+    pub fn from_name(s : &str) -> Option<Format> {
+        match &s.to_lowercase()[..] {
+            "png"          => Some(Format::Png),
+            "jpg" | "jpeg" => Some(Format::Jpeg),
+            "ppm"          => Some(Format::Ppm),
+            _ => None
+        }
+    }
+}
+
+/// Part of the image to render, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub x : i32,
+    pub y : i32,
+    pub w : i32,
+    pub h : i32
+}
+
+/// Built in integrators `render` can pick by settings,
+/// other ones go through `render_with`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IntegratorKind {
+    /// Direct light with soft shadows plus mirror reflections, see `shade`.
+    Whitted,
+    /// Monte Carlo path tracing with the given max depth, see `trace_path`.
+    Path(i32),
+    /// Ambient occlusion within the given distance.
+    AmbientOcclusion(f32),
+    /// Shading normals as colours.
+    Normals
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderSettings {
+    pub threads       : i32,
+    pub samples       : i32,
+    /// Whole camera image if None.
+    pub region        : Option<Region>,
+    pub integrator    : IntegratorKind,
+    /// Shadow rays per area light for the Whitted integrator.
+    pub light_samples : i32,
+    /// Exposure in stops, every one doubles the brightness of the output.
+    pub exposure      : f32,
+    pub filter        : Filter
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            threads       : 4,
+            samples       : 1,
+            region        : None,
+            integrator    : IntegratorKind::Whitted,
+            light_samples : 16,
+            exposure      : 0.0,
+            filter        : Filter::Box
+        }
+    }
+}
+
+/// 8 bit RGB image, rows from top to bottom.
+pub struct Image {
+    pub w    : i32,
+    pub h    : i32,
+    pub data : Vec<u8>
+}
+
+impl Image {
+    pub fn pixel(&self, x : i32, y : i32) -> (u8, u8, u8) {
+        let i = ((y * self.w + x) * 3) as usize;
+        (self.data[i], self.data[i + 1], self.data[i + 2])
+    }
+
+    pub fn save(&self, path : &str, format : Format) -> Result<(), String> {
+        let mut file = try!(File::create(path).map_err(|e| e.to_string()));
+        match format {
+            Format::Ppm => {
+                try!(write!(file, "P6\n{} {}\n255\n", self.w, self.h).map_err(|e| e.to_string()));
+                file.write_all(&self.data).map_err(|e| e.to_string())
+            }
+            Format::Png | Format::Jpeg => {
+                let format = if format == Format::Png { ImageFormat::PNG } else { ImageFormat::JPEG };
+                let img = ImageBuffer::<Rgb<u8>, Vec<u8>>::from_raw(
+                    self.w as u32, self.h as u32, self.data.clone()).unwrap();
+                DynamicImage::ImageRgb8(img).save(&mut file, format).map_err(|e| e.to_string())
+            }
+        }
+    }
+}
+
+/// Columns and rows of the grid `samples` samples are spread over: exactly one
+/// sample per cell, as square as `samples` allows (2 gives two columns, 6 gives 3x2).
+fn sample_grid(samples : i32) -> (i32, i32) {
+    let rows = (1 .. (samples as f32).sqrt() as i32 + 1).filter(|r| samples % r == 0).last().unwrap_or(1);
+    (samples / rows, rows)
+}
+
+/// Sub-pixel offset of the i-th of `samples` samples: jittered inside its own cell
+/// of a grid covering the whole pixel, a single sample goes through the pixel center.
+fn sample_offset(i : i32, samples : i32, sampler : &mut Sampler) -> (f32, f32) {
+    if samples == 1 {
+        return (0.5, 0.5);
+    }
+    let (cols, rows) = sample_grid(samples);
+    (((i % cols) as f32 + sampler.next_1d()) / cols as f32,
+     ((i / cols) as f32 + sampler.next_1d()) / rows as f32)
+}
+
+fn render_part<I : Integrator>(scene : &Scene, integrator : &I, settings : &RenderSettings,
+                               region : Region, start : i32, end : i32, film : &mut Film) {
+    let mut sampler = Sampler::new(start as u32);
+    for pos in start .. end {
+        let x = pos % region.w;
+        let y = pos / region.w;
+        for i in 0 .. settings.samples {
+            let (dx, dy) = sample_offset(i, settings.samples, &mut sampler);
+            let lens = sampler.next_2d();
+            let ray = scene.cam.get_lens_ray((region.x + x) as f32 + dx, (region.y + y) as f32 + dy, lens);
+            film.add_sample(x as f32 + dx, y as f32 + dy, integrator.li(scene, ray, &mut sampler));
+        }
+    }
+}
+
+fn render_thread<I : Integrator>(passed : &Mutex<i32>, scene : &Scene, integrator : &I,
+                                 settings : &RenderSettings, region : Region) -> Film {
+    let mut film = Film::new(region.w, region.h, settings.filter);
+    let total = region.w * region.h;
+
+    while {
+        let passed = {
+            let mut passed = passed.lock().unwrap();
+            *passed += CHUNK_SIZE;
+            *passed
+        };
+        let start = passed - CHUNK_SIZE;
+        if start < total {
+            render_part(scene, integrator, settings, region, start, min(total, start + CHUNK_SIZE), &mut film);
+            true
+        } else {
+            false
+        }
+    } {}
+    film
+}
+
+/// Renders the scene, or its `settings.region` part, from the scene camera
+/// with the integrator picked by `settings.integrator`.
+pub fn render(scene : &Scene, settings : &RenderSettings) -> Image {
+    match settings.integrator {
+        IntegratorKind::Whitted =>
+            render_with(scene, &Whitted { light_samples : settings.light_samples }, settings),
+        IntegratorKind::Path(max_depth) =>
+            render_with(scene, &PathTracer { max_depth : max_depth }, settings),
+        IntegratorKind::AmbientOcclusion(distance) =>
+            render_with(scene, &AmbientOcclusion { distance : distance, samples : 16 }, settings),
+        IntegratorKind::Normals => render_with(scene, &Normals, settings)
+    }
+}
+
+/// Same as `render` with any integrator, `settings.integrator` is ignored.
+pub fn render_with<I : Integrator + Sync>(scene : &Scene, integrator : &I, settings : &RenderSettings) -> Image {
+    let region = settings.region.unwrap_or(Region { x : 0, y : 0, w : scene.cam.w, h : scene.cam.h });
+    let passed = Mutex::new(0);
+    let mut film = Film::new(region.w, region.h, settings.filter);
+
+    thread::scope(|s| {
+        let threads = (0 .. settings.threads).map(|_| {
+            s.spawn(|| render_thread(&passed, scene, integrator, settings, region))
+        }).collect::<Vec<_>>();
+        for thread in threads {
+            film.merge(&thread.join().unwrap());
+        }
+    });
+
+    let scale = settings.exposure.exp2();
+    let mut buf = Vec::with_capacity((region.w * region.h * 3) as usize);
+    for y in 0 .. region.h {
+        for x in 0 .. region.w {
+            // negative filter lobes can push a pixel below black
+            let color = film.pixel(x, y) * scale;
+            buf.push(color.x.max(0.0).min(255.0) as u8);
+            buf.push(color.y.max(0.0).min(255.0) as u8);
+            buf.push(color.z.max(0.0).min(255.0) as u8);
+        }
+    }
+
+    Image {
+        w    : region.w,
+        h    : region.h,
+        data : buf
+    }
+}
+
+#[test]
+fn test_sample_offset() {
+    let mut sampler = Sampler::new(1);
+    assert_eq!(sample_offset(0, 1, &mut sampler), (0.5, 0.5));
+    for &n in &[2, 3, 4, 6, 7, 16] {
+        let (cols, rows) = sample_grid(n);
+        assert_eq!(cols * rows, n);
+        // every cell of the grid gets exactly one sample, so they cover [0, 1)^2
+        let mut cells = (0 .. n).map(|i| {
+            let (x, y) = sample_offset(i, n, &mut sampler);
+            assert!(x >= 0.0 && x < 1.0 && y >= 0.0 && y < 1.0);
+            ((x * cols as f32) as i32, (y * rows as f32) as i32)
+        }).collect::<Vec<_>>();
+        cells.sort();
+        cells.dedup();
+        assert_eq!(cells.len(), n as usize);
+    }
+    assert_eq!(sample_grid(2), (2, 1));
+    assert_eq!(sample_grid(3), (3, 1));
+}
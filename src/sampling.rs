@@ -0,0 +1,112 @@
+use na::*;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+use std::f32::consts::PI;
+
+/// Source of the random numbers for one rendering thread.
+/// Seeded by the image chunk, so the picture doesn't depend on the thread count.
+pub struct Sampler {
+    rng : XorShiftRng
+}
+
+impl Sampler {
+    pub fn new(seed : u32) -> Sampler {
+        // xorshift must not start from all zeros
+        Sampler { rng : XorShiftRng::from_seed([seed, seed ^ 0x9e3779b9, 0x6a09e667, 0xbb67ae85]) }
+    }
+
+    /// Uniform number in [0, 1).
+    pub fn next_1d(&mut self) -> f32 {
+        self.rng.gen::<f32>()
+    }
+
+    pub fn next_2d(&mut self) -> Vec2<f32> {
+        Vec2::new(self.next_1d(), self.next_1d())
+    }
+}
+
+/// Maps a point of the unit square onto the unit disk, keeping
+/// the samples stratified (Shirley-Chiu concentric mapping).
+pub fn sample_disk(u : f32, v : f32) -> Vec2<f32> {
+    let a = u * 2.0 - 1.0;
+    let b = v * 2.0 - 1.0;
+    if a == 0.0 && b == 0.0 {
+        return Vec2::new(0.0, 0.0);
+    }
+    let (r, phi) = if a.abs() > b.abs() {
+        (a, PI / 4.0 * (b / a))
+    } else {
+        (b, PI / 2.0 - PI / 4.0 * (a / b))
+    };
+    Vec2::new(r * phi.cos(), r * phi.sin())
+}
+
+/// Direction in the local frame around +z, with density cos(theta) / PI.
+pub fn cosine_hemisphere(u : f32, v : f32) -> Vec3<f32> {
+    let d = sample_disk(u, v);
+    let z = (1.0 - d.x * d.x - d.y * d.y).max(0.0).sqrt();
+    Vec3::new(d.x, d.y, z)
+}
+
+/// Uniformly distributed point of the unit sphere.
+pub fn uniform_sphere(u : f32, v : f32) -> Vec3<f32> {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Direction in the local frame around +z, uniform inside the cone of `cos_max`.
+pub fn uniform_cone(u : f32, v : f32, cos_max : f32) -> Vec3<f32> {
+    let z = 1.0 - u * (1.0 - cos_max);
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
+/// Weight of a sample taken with density `pdf` when another strategy
+/// could have produced it with density `other` (Veach's power heuristic).
+pub fn power_heuristic(pdf : f32, other : f32) -> f32 {
+    let (a, b) = (pdf * pdf, other * other);
+    if a + b > 0.0 { a / (a + b) } else { 0.0 }
+}
+
+/// Two unit vectors completing `n` to an orthonormal basis.
+pub fn orthonormal_basis(n : Vec3<f32>) -> (Vec3<f32>, Vec3<f32>) {
+    let t = if n.x.abs() > 0.9 {
+        Vec3::new(0.0, 1.0, 0.0)
+    } else {
+        Vec3::new(1.0, 0.0, 0.0)
+    };
+    let s = n.cross(&t).normalize();
+    (s, n.cross(&s))
+}
+
+/// Local direction (z along `n`) in world space.
+pub fn to_world(local : Vec3<f32>, n : Vec3<f32>) -> Vec3<f32> {
+    let (s, t) = orthonormal_basis(n);
+    s * local.x + t * local.y + n * local.z
+}
+
+#[test]
+fn test_sampling() {
+    for &(u, v) in &[(0.0, 0.0), (0.3, 0.7), (0.99, 0.5), (0.5, 0.5)] {
+        let d = cosine_hemisphere(u, v);
+        assert!(d.z >= 0.0);
+        assert!((d.norm() - 1.0).abs() < 0.0001);
+        assert!((uniform_sphere(u, v).norm() - 1.0).abs() < 0.0001);
+        assert!(uniform_cone(u, v, 0.9).z >= 0.9);
+    }
+    let n = Vec3::new(0.0, 0.6, 0.8);
+    let (s, t) = orthonormal_basis(n);
+    assert!(s.dot(&n).abs() < 0.0001 && t.dot(&n).abs() < 0.0001 && s.dot(&t).abs() < 0.0001);
+    assert!((to_world(Vec3::new(0.0, 0.0, 1.0), n) - n).norm() < 0.0001);
+
+    let (mut a, mut b) = (Sampler::new(7), Sampler::new(7));
+    for _ in 0 .. 10 {
+        let x = a.next_1d();
+        assert!(x >= 0.0 && x < 1.0);
+        assert_eq!(x, b.next_1d());
+    }
+}
@@ -1,5 +1,7 @@
 use core::*;
 
+use std::f32::MAX;
+
 #[derive(Clone, Debug, Copy, PartialEq)]
 pub struct BBox {
     pub bmin : Vec3<f32>,
@@ -15,12 +17,8 @@ impl BBox {
         let t5 = (self.bmin.z - r.pos.z) / r.dir.z;
         let t6 = (self.bmax.z - r.pos.z) / r.dir.z;
 
-        let tmin = t1.min(t2).max(t3.min(t4)).max(t5.min(t6));
-        let tmax = t1.max(t2).min(t3.max(t4)).min(t5.max(t6));
-
-        if tmax < 0.0 {
-            return None;
-        }
+        let tmin = t1.min(t2).max(t3.min(t4)).max(t5.min(t6)).max(r.t_min);
+        let tmax = t1.max(t2).min(t3.max(t4)).min(t5.max(t6)).min(r.t_max);
 
         if tmin > tmax {
              return None;
@@ -28,6 +26,25 @@ impl BBox {
         return Some(tmin);
     }
 
+    /// Box containing nothing, grows from there with `union`.
+    pub fn empty() -> BBox {
+        BBox {
+            bmin : Vec3::new(MAX, MAX, MAX),
+            bmax : Vec3::new(-MAX, -MAX, -MAX)
+        }
+    }
+
+    pub fn union(&self, bbox : &BBox) -> BBox {
+        BBox {
+            bmin : BBox::vmin(&self.bmin, &bbox.bmin),
+            bmax : BBox::vmax(&self.bmax, &bbox.bmax)
+        }
+    }
+
+    pub fn center(&self) -> Vec3<f32> {
+        (self.bmin + self.bmax) * 0.5
+    }
+
     pub fn split_left(&self, x : f32, axis : Axis) -> BBox {
         BBox { bmin : self.bmin, bmax : axis.set(self.bmax, x) }
     }
@@ -62,7 +79,6 @@ impl BBox {
             v.z >= self.bmin.z && v.z <= self.bmax.z
     }
 
-    #[allow(dead_code)]
     pub fn square(&self) -> f32 {
         let a = (self.bmin.x - self.bmax.x).abs();
         let b = (self.bmin.y - self.bmax.y).abs();
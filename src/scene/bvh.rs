@@ -0,0 +1,291 @@
+use core::*;
+
+use scene::obj_intersect;
+
+const BINS : usize = 12;
+const MAX_LEAF_SIZE : usize = 4;
+/// Cost of visiting a node, relative to a ray-primitive test.
+const TRAVERSAL_COST : f32 = 0.125;
+
+/// Bounding volume hierarchy, flattened into an array in depth first order:
+/// first child of an inner node goes right after it, the second one is at `offset`.
+/// Leaves own `count` objects starting from `offset`, so every object is stored once.
+pub struct BVHRoot {
+    objects : Vec<BoxedObject>,
+    /// Index every object had in the `build_bvh` input, in tree order.
+    indices : Vec<usize>,
+    nodes   : Vec<BVHNode>
+}
+
+#[derive(Clone, Copy, Debug)]
+struct BVHNode {
+    bbox   : BBox,
+    offset : usize,
+    count  : usize,
+    axis   : Axis
+}
+
+struct BuildItem {
+    bbox   : BBox,
+    center : Vec3<f32>,
+    index  : usize
+}
+
+fn object_bbox(obj : &BoxedObject) -> BBox {
+    BBox { bmin : obj.get_min_bound(), bmax : obj.get_max_bound() }
+}
+
+fn longest_axis(bbox : &BBox) -> Axis {
+    let d = bbox.bmax - bbox.bmin;
+    if d.x > d.y && d.x > d.z {
+        Axis::X
+    } else if d.y > d.z {
+        Axis::Y
+    } else {
+        Axis::Z
+    }
+}
+
+/// Binned SAH split: index of the last bin going to the first child,
+/// None if a leaf is cheaper.
+fn find_split(items : &[BuildItem], bbox : &BBox, axis : Axis, lo : f32, hi : f32) -> Option<usize> {
+    let bin_of = |c : Vec3<f32>| (((axis.get(c) - lo) / (hi - lo) * BINS as f32) as usize).min(BINS - 1);
+
+    let mut counts = [0; BINS];
+    let mut bounds = [BBox::empty(); BINS];
+    for item in items {
+        let b = bin_of(item.center);
+        counts[b] += 1;
+        bounds[b] = bounds[b].union(&item.bbox);
+    }
+
+    let total = bbox.square().max(1e-12);
+    let mut best : Option<(f32, usize)> = None;
+    for split in 0 .. BINS - 1 {
+        let (mut left, mut right) = (BBox::empty(), BBox::empty());
+        let (mut nl, mut nr) = (0, 0);
+        for b in 0 .. split + 1 {
+            left = left.union(&bounds[b]);
+            nl += counts[b];
+        }
+        for b in split + 1 .. BINS {
+            right = right.union(&bounds[b]);
+            nr += counts[b];
+        }
+        if nl == 0 || nr == 0 {
+            continue;
+        }
+        let cost = TRAVERSAL_COST +
+            (nl as f32 * left.square() + nr as f32 * right.square()) / total;
+        if best.map_or(true, |(best_cost, _)| cost < best_cost) {
+            best = Some((cost, split));
+        }
+    }
+
+    match best {
+        Some((cost, _)) if items.len() <= MAX_LEAF_SIZE && cost >= items.len() as f32 => None,
+        Some((_, split)) => Some(split),
+        None => None
+    }
+}
+
+fn build_node(items : &mut [BuildItem], first : usize, nodes : &mut Vec<BVHNode>) {
+    let bbox = items.iter().fold(BBox::empty(), |b, item| b.union(&item.bbox));
+    let centers = items.iter().fold(BBox::empty(), |b, item| {
+        b.union(&BBox { bmin : item.center, bmax : item.center })
+    });
+    let axis = longest_axis(&centers);
+    let node = nodes.len();
+    nodes.push(BVHNode { bbox : bbox, offset : first, count : items.len(), axis : axis });
+
+    let (lo, hi) = (axis.get(centers.bmin), axis.get(centers.bmax));
+    if items.len() <= 1 || hi <= lo {
+        return;
+    }
+    let split = match find_split(items, &bbox, axis, lo, hi) {
+        Some(split) => split,
+        None => return
+    };
+
+    let mut mid = 0;
+    for i in 0 .. items.len() {
+        let b = (((axis.get(items[i].center) - lo) / (hi - lo) * BINS as f32) as usize).min(BINS - 1);
+        if b <= split {
+            items.swap(i, mid);
+            mid += 1;
+        }
+    }
+
+    build_node(&mut items[.. mid], first, nodes);
+    let second = nodes.len();
+    build_node(&mut items[mid ..], first + mid, nodes);
+    nodes[node].offset = second;
+    nodes[node].count = 0;
+}
+
+pub fn build_bvh(objects : Vec<BoxedObject>) -> BVHRoot {
+    let mut items = objects.iter().enumerate().map(|(i, obj)| {
+        let bbox = object_bbox(obj);
+        BuildItem { bbox : bbox, center : bbox.center(), index : i }
+    }).collect::<Vec<BuildItem>>();
+
+    let mut nodes = Vec::with_capacity(objects.len() * 2);
+    if !items.is_empty() {
+        build_node(&mut items, 0, &mut nodes);
+    }
+
+    let mut slots = objects.into_iter().map(Some).collect::<Vec<Option<BoxedObject>>>();
+    BVHRoot {
+        objects : items.iter().map(|item| slots[item.index].take().unwrap()).collect(),
+        indices : items.iter().map(|item| item.index).collect(),
+        nodes   : nodes
+    }
+}
+
+impl BVHRoot {
+    pub fn bbox(&self) -> BBox {
+        self.nodes.first().map_or(BBox::empty(), |node| node.bbox)
+    }
+
+    /// Objects in tree order. Replacing or moving them is fine as long as
+    /// `refit` is called before the next intersection.
+    pub fn objects_mut(&mut self) -> &mut [BoxedObject] {
+        &mut self.objects
+    }
+
+    /// Closest hit, with the object hit and the index it had in the `build_bvh` input.
+    pub fn intersect_index(&self, r : Ray) -> Option<(f32, IntersectData, usize, &BoxedObject)> {
+        let mut closest : Option<(f32, IntersectData, usize)> = None;
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            // nodes behind the closest hit so far are skipped
+            let bound = match closest {
+                Some((t, _, _)) => r.clip(t),
+                None => r
+            };
+            if node.bbox.intersect(bound).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                for j in node.offset .. node.offset + node.count {
+                    let r = match closest {
+                        Some((t, _, _)) => r.clip(t),
+                        None => r
+                    };
+                    if let Some((t, data)) = obj_intersect(&self.objects[j], r) {
+                        closest = Some((t, data, j));
+                    }
+                }
+            } else if node.axis.get(r.dir) < 0.0 {
+                // second child is nearer, visit it first
+                stack.push(i + 1);
+                stack.push(node.offset);
+            } else {
+                stack.push(node.offset);
+                stack.push(i + 1);
+            }
+        }
+        closest.map(|(t, data, j)| (t, data, self.indices[j], &self.objects[j]))
+    }
+
+    /// Recomputes node bounds after the objects moved, keeping the topology.
+    pub fn refit(&mut self) {
+        for i in (0 .. self.nodes.len()).rev() {
+            let node = self.nodes[i];
+            self.nodes[i].bbox = if node.count > 0 {
+                self.objects[node.offset .. node.offset + node.count].iter()
+                    .fold(BBox::empty(), |b, obj| b.union(&object_bbox(obj)))
+            } else {
+                self.nodes[i + 1].bbox.union(&self.nodes[node.offset].bbox)
+            };
+        }
+    }
+}
+
+impl RayLover for BVHRoot {}
+impl Projectable for BVHRoot {
+    fn get_min(&self, v : Vec3<f32>) -> Vec3<f32> {
+        BBox::vmin(&v, &self.bbox().bmin)
+    }
+    fn get_max(&self, v : Vec3<f32>) -> Vec3<f32> {
+        BBox::vmax(&v, &self.bbox().bmax)
+    }
+    fn get_min_bound(&self) -> Vec3<f32> {
+        self.bbox().bmin
+    }
+    fn get_max_bound(&self) -> Vec3<f32> {
+        self.bbox().bmax
+    }
+    fn in_box(&self, bbox : BBox) -> bool {
+        self.bbox().overlap(&bbox)
+    }
+}
+impl Intersectable for BVHRoot {
+    fn intersect(&self, r : Ray) -> Option<(f32, IntersectData)> {
+        self.intersect_index(r).map(|(t, data, _, _)| (t, data))
+    }
+
+    fn occluded(&self, r : Ray, t_max : f32) -> bool {
+        let mut stack = Vec::with_capacity(64);
+        if !self.nodes.is_empty() {
+            stack.push(0);
+        }
+        while let Some(i) = stack.pop() {
+            let node = &self.nodes[i];
+            if node.bbox.intersect(r.clip(t_max)).is_none() {
+                continue;
+            }
+            if node.count > 0 {
+                let objects = &self.objects[node.offset .. node.offset + node.count];
+                if objects.iter().any(|obj| obj.occluded(r, t_max)) {
+                    return true;
+                }
+            } else {
+                stack.push(node.offset);
+                stack.push(i + 1);
+            }
+        }
+        false
+    }
+}
+
+#[test]
+fn test_bvh_spheres() {
+    use testing::*;
+
+    let bvh = build_bvh(gen_test_sphere_grid());
+    let spheres = gen_test_sphere_grid();
+    assert!(bvh.nodes.len() > 1);
+    assert!(bvh.nodes.len() < spheres.len() * 2);
+    assert_eq!(bvh.objects.len(), spheres.len());
+
+    let pos = Vec3::new(-10.0, -7.0, -5.0);
+    for sphere in &spheres {
+        let ray = Ray::new(pos, (sphere.get_min_bound() + sphere.get_max_bound()) * 0.5 - pos);
+        let ray = Ray::new(pos, ray.dir.normalize());
+        let expected = spheres.intersect(ray).map(|(t, _)| t);
+        assert_eq!(bvh.intersect(ray).map(|(t, _)| t), expected);
+        // the index is the one in the input, whatever order the tree keeps
+        let (_, _, index, _) = bvh.intersect_index(ray).unwrap();
+        assert_eq!(spheres[index].intersect(ray).map(|(t, _)| t), expected);
+        assert!(bvh.occluded(ray, expected.unwrap() + 0.01));
+        assert!(!bvh.occluded(ray, expected.unwrap() - 0.01));
+    }
+    assert!(bvh.intersect(Ray::new(pos, Vec3::new(-1.0, 0.0, 0.0))).is_none());
+}
+
+#[test]
+fn test_bvh_refit() {
+    use testing::*;
+
+    let mut bvh = build_bvh(gen_test_sphere_grid());
+    let bbox = bvh.bbox();
+    bvh.objects_mut()[0] = gen_test_sphere(Vec3::new(100.0, 0.0, 0.0), 1.0);
+    bvh.refit();
+    assert!(bvh.bbox().bmax.x > bbox.bmax.x);
+    assert!(bvh.intersect(Ray::new(Vec3::new(100.0, 0.0, -10.0), Vec3::new(0.0, 0.0, 1.0))).is_some());
+}
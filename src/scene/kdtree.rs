@@ -1,14 +1,7 @@
-use std::cmp::Ordering;
 use std::cmp::Ordering::*;
-use std::f32::MAX;
 
 use core::*;
 
-use objects::mesh::*;
-use objects::sphere::*;
-
-use materials::*;
-
 pub struct KDTreeRoot {
     objects : Vec<BoxedObject>,
     tree    : Box<KDTreeNode>
@@ -21,6 +14,52 @@ pub struct KDTreeNode<> {
     right   : Option<Box<KDTreeNode>>
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SplitMethod {
+    /// Spatial median of the longest axis.
+    Median,
+    /// Surface Area Heuristic, sweeps over all primitive bounds.
+    Sah
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct KDTreeSettings {
+    pub split          : SplitMethod,
+    /// Cost of visiting an inner node.
+    pub traversal_cost : f32,
+    /// Cost of a single ray-primitive test.
+    pub intersect_cost : f32,
+    /// Part of the cost saved when one side of the split is empty, 0.0 .. 1.0.
+    pub empty_bonus    : f32,
+    /// Nodes with this many primitives or less are never split.
+    pub max_leaf_size  : usize,
+    pub max_depth      : usize
+}
+
+impl Default for KDTreeSettings {
+    fn default() -> KDTreeSettings {
+        KDTreeSettings {
+            split          : SplitMethod::Sah,
+            traversal_cost : 1.0,
+            intersect_cost : 80.0,
+            empty_bonus    : 0.5,
+            max_leaf_size  : 1,
+            max_depth      : 24
+        }
+    }
+}
+
+impl KDTreeSettings {
+    /// The old builder: median splits down to 30 primitives per leaf.
+    pub fn median() -> KDTreeSettings {
+        KDTreeSettings {
+            split         : SplitMethod::Median,
+            max_leaf_size : 29,
+            .. KDTreeSettings::default()
+        }
+    }
+}
+
 impl KDTreeNode {
     pub fn intersect_node(&self, r : Ray) -> Option<f32> {
             self.bbox.intersect(r)
@@ -33,100 +72,166 @@ impl KDTreeNode {
             .collect::<Vec<&BoxedObject>>()
 
     }
-    fn intersect(&self, objects : &Vec<BoxedObject>, r : Ray) -> Option<(f32, IntersectData)> {
+    /// Counts ray-primitive tests in `tests`.
+    fn intersect(&self, objects : &Vec<BoxedObject>, r : Ray, tests : &mut usize) -> Option<(f32, IntersectData)> {
         match (&self.left, &self.right) {
             (&None, &None) => match self.intersect_node(r) {
-                Some(_) => self.objects(objects).intersect(r),
+                Some(_) => {
+                    *tests += self.indices.len();
+                    self.objects(objects).intersect(r)
+                }
                 None => None
             },
-            (&None, &Some(ref x)) | (&Some(ref x), &None) => x.intersect(&objects, r),
+            (&None, &Some(ref x)) | (&Some(ref x), &None) => x.intersect(&objects, r, tests),
             (&Some(ref x), &Some(ref y)) => match (x.intersect_node(r), y.intersect_node(r)) {
                 (None, None) => None,
-                (None, Some(_)) => y.intersect(objects, r),
-                (Some(_), None) => x.intersect(objects, r),
-                (Some(lx), Some(ly)) if lx > ly => y.intersect_or_next(x, objects, r),
-                (Some(_), Some(_))  => x.intersect_or_next(y, objects, r),
+                (None, Some(_)) => y.intersect(objects, r, tests),
+                (Some(_), None) => x.intersect(objects, r, tests),
+                (Some(lx), Some(ly)) if lx > ly => y.intersect_or_next(x, objects, r, tests),
+                (Some(_), Some(_))  => x.intersect_or_next(y, objects, r, tests),
             }
         }
     }
-    fn intersect_or_next(&self, next : &KDTreeNode, objects : &Vec<BoxedObject>, r : Ray) -> Option<(f32, IntersectData)>{
-        match self.intersect(objects, r.clone()) {
-            res @ Some(_) => res,
-            None => next.intersect(objects, r)
+    /// Nearer child first; the farther one is still visited, but only up to
+    /// the hit found, since primitives straddling the split can hit beyond the near box.
+    fn intersect_or_next(&self, next : &KDTreeNode, objects : &Vec<BoxedObject>, r : Ray, tests : &mut usize) -> Option<(f32, IntersectData)>{
+        match self.intersect(objects, r, tests) {
+            Some((t, data)) => match next.intersect(objects, r.clip(t), tests) {
+                res @ Some(_) => res,
+                None => Some((t, data))
+            },
+            None => next.intersect(objects, r, tests)
+        }
+    }
+    fn occluded(&self, objects : &Vec<BoxedObject>, r : Ray, t_max : f32) -> bool {
+        if self.intersect_node(r.clip(t_max)).is_none() {
+            return false;
         }
+        match (&self.left, &self.right) {
+            (&None, &None) => self.indices.iter().any(|i| objects[*i as usize].occluded(r, t_max)),
+            (&None, &Some(ref x)) | (&Some(ref x), &None) => x.occluded(objects, r, t_max),
+            (&Some(ref x), &Some(ref y)) => x.occluded(objects, r, t_max) || y.occluded(objects, r, t_max)
+        }
+    }
+}
+
+impl KDTreeRoot {
+    /// Number of ray-primitive tests needed to trace the ray, for comparing trees.
+    pub fn count_tests(&self, r : Ray) -> usize {
+        let mut tests = 0;
+        self.tree.intersect(&self.objects, r, &mut tests);
+        tests
     }
 }
 
 impl RayLover for KDTreeRoot {}
-impl Projectable for KDTreeRoot {}
+impl Projectable for KDTreeRoot {
+    fn get_min(&self, v : Vec3<f32>) -> Vec3<f32> {
+        BBox::vmin(&v, &self.tree.bbox.bmin)
+    }
+    fn get_max(&self, v : Vec3<f32>) -> Vec3<f32> {
+        BBox::vmax(&v, &self.tree.bbox.bmax)
+    }
+    fn get_min_bound(&self) -> Vec3<f32> {
+        self.tree.bbox.bmin
+    }
+    fn get_max_bound(&self) -> Vec3<f32> {
+        self.tree.bbox.bmax
+    }
+    fn in_box(&self, bbox : BBox) -> bool {
+        self.tree.bbox.overlap(&bbox)
+    }
+}
 impl Intersectable for KDTreeRoot {
     fn intersect(&self, r: Ray) -> Option<(f32, IntersectData)> {
-        return self.tree.intersect(&self.objects, r)
+        return self.tree.intersect(&self.objects, r, &mut 0)
+    }
+    fn occluded(&self, r : Ray, t_max : f32) -> bool {
+        self.tree.occluded(&self.objects, r, t_max)
     }
 }
 
-fn compare_objects(axis : &Axis, a : &BoxedObject, b : &BoxedObject) -> Ordering {
-    axis.get(a.get_min_bound()).partial_cmp(
-        &axis.get(b.get_min_bound())).unwrap_or(Less)
+#[derive(Clone, Copy, Debug)]
+struct Edge {
+    pos   : f32,
+    start : bool,
+    index : i32
 }
 
-#[allow(dead_code)]
-fn split_with_sort(bbox : BBox, indcs : &Vec<i32>, objects : &Vec<BoxedObject>) -> ((BBox, Vec<i32>), (BBox, Vec<i32>)) {
-    let mut indcs = indcs.clone();
-    let mut min_sah = MAX;
-    let mut min_axis = Axis::X;
-    let mut min_split = 0.0;
-
-    for axis in &[Axis::X, Axis::Y, Axis::Z] {
-        indcs.sort_by(|a, b| {compare_objects(&axis, &objects[*a as usize], &objects[*b as usize])});
+/// Bounds of all the primitives along the axis, sorted.
+/// Starts go before ends at the same position, so a flat primitive
+/// lying on the split plane never ends up in neither child.
+fn sorted_edges(axis : Axis, indcs : &Vec<i32>, objects : &Vec<BoxedObject>) -> Vec<Edge> {
+    let mut edges = Vec::with_capacity(indcs.len() * 2);
+    for i in indcs {
+        let obj = &objects[*i as usize];
+        edges.push(Edge { pos : axis.get(obj.get_min_bound()), start : true, index : *i });
+        edges.push(Edge { pos : axis.get(obj.get_max_bound()), start : false, index : *i });
+    }
+    edges.sort_by(|a, b| {
+        a.pos.partial_cmp(&b.pos).unwrap_or(Equal).then(b.start.cmp(&a.start))
+    });
+    edges
+}
 
-        for (i, c) in (1 .. indcs.len()).zip(1..) {
-            const CONST_EMPTY : f32 = 0.1;
+/// Finds the cheapest split by the Surface Area Heuristic.
+/// None if no split is cheaper than a leaf.
+fn split_sah(bbox : BBox, indcs : &Vec<i32>, objects : &Vec<BoxedObject>, settings : &KDTreeSettings)
+             -> Option<((BBox, Vec<i32>), (BBox, Vec<i32>))> {
+    let total = bbox.square();
+    if total <= 0.0 {
+        return None;
+    }
+    let count = indcs.len();
+    let leaf_cost = settings.intersect_cost * count as f32;
+    let mut best : Option<(f32, Axis, usize)> = None;
 
-            let split = axis.get(objects[i].get_min_bound());
-            if split == axis.get(bbox.bmin) {
-                continue;
+    for axis in &[Axis::X, Axis::Y, Axis::Z] {
+        let edges = sorted_edges(*axis, indcs, objects);
+        let (lo, hi) = (axis.get(bbox.bmin), axis.get(bbox.bmax));
+        let mut below = 0;
+        let mut above = count;
+        for (i, edge) in edges.iter().enumerate() {
+            if !edge.start {
+                above -= 1;
             }
-
-            let square_left  = bbox.split_left(split, *axis).square();
-            let square_right = bbox.split_right(split, *axis).square();
-            let count_left = c as f32;
-            let count_right = (indcs.len() - c) as f32;
-            let sah = CONST_EMPTY + square_left * count_left + square_right * count_right;
-            if min_sah > sah {
-                // println!("sah: {} num: {} split: {} l: {} r : {} sl : {} sr : {}", sah, c, split, count_left, count_right, square_left, square_right);
-                min_sah = sah;
-                min_axis = axis.clone();
-                min_split = split;
+            if edge.pos > lo && edge.pos < hi {
+                let p_below = bbox.split_left(edge.pos, *axis).square() / total;
+                let p_above = bbox.split_right(edge.pos, *axis).square() / total;
+                let bonus = if below == 0 || above == 0 { settings.empty_bonus } else { 0.0 };
+                let cost = settings.traversal_cost + settings.intersect_cost * (1.0 - bonus) *
+                    (p_below * below as f32 + p_above * above as f32);
+                if best.map_or(true, |(best_cost, _, _)| cost < best_cost) {
+                    best = Some((cost, *axis, i));
+                }
+            }
+            if edge.start {
+                below += 1;
             }
         }
     }
 
-    let bbox_left = bbox.split_left(min_split, min_axis);
-    let bbox_right = bbox.split_right(min_split, min_axis);
-    let mut lefts = Vec::new();
-    let mut rights = Vec::new();
-    for i in &indcs {
-        //println!("{:?}", bbox_left);
-        if objects[*i as usize].in_box(bbox_left) {
-            lefts.push(*i);
-        }
-        //println!("{:?}", bbox_right);
-        if objects[*i as usize].in_box(bbox_right) {
-            rights.push(*i);
+    match best {
+        Some((cost, axis, offset)) if cost < leaf_cost => {
+            let edges = sorted_edges(axis, indcs, objects);
+            let split = edges[offset].pos;
+            let lefts = edges[.. offset].iter()
+                .filter(|e| e.start).map(|e| e.index).collect();
+            let rights = edges[offset + 1 ..].iter()
+                .filter(|e| !e.start).map(|e| e.index).collect();
+            Some(((bbox.split_left(split, axis), lefts),
+                  (bbox.split_right(split, axis), rights)))
         }
+        _ => None
     }
-
-    //println!("{} {} {} {} {:?} {:?} {:?}", indcs.len(), lefts.len(), rights.len(), min_split, bbox, bbox_left, bbox_right);
-
-    assert!(bbox_left != bbox);
-    assert!(bbox_right != bbox);
-    ((bbox_left, lefts),
-     (bbox_right, rights))
 }
 
-fn split(bbox : BBox, indcs : &Vec<i32>, objects : &Vec<BoxedObject>) -> ((BBox, Vec<i32>), (BBox, Vec<i32>)) {
-    split_easy(bbox, indcs, objects)
+fn split(bbox : BBox, indcs : &Vec<i32>, objects : &Vec<BoxedObject>, settings : &KDTreeSettings)
+         -> Option<((BBox, Vec<i32>), (BBox, Vec<i32>))> {
+    match settings.split {
+        SplitMethod::Median => Some(split_easy(bbox, indcs, objects)),
+        SplitMethod::Sah => split_sah(bbox, indcs, objects, settings)
+    }
 }
 
 fn split_easy(bbox : BBox, indcs : &Vec<i32>, objects : &Vec<BoxedObject>) -> ((BBox, Vec<i32>), (BBox, Vec<i32>)) {
@@ -163,22 +268,10 @@ fn split_easy(bbox : BBox, indcs : &Vec<i32>, objects : &Vec<BoxedObject>) -> ((
      (bbox_right, rights))
 }
 
-#[allow(dead_code)]
-fn gen_test_spheres() -> (Vec<BoxedObject>, BBox) {
-    let spheres = vec![
-        Box::new(Sphere {
-            pos : Vec3::new(2.5, 2.5, 2.5),
-            radius : 5.0,
-            material : test_mat()}) as BoxedObject,
-        Box::new(Sphere {
-            pos : Vec3::new(8.5, 2.5, 2.5),
-            radius : 5.0,
-            material : test_mat()})];
-    let bbox = spheres.get_bound_box();
-    (spheres, bbox)
-}
 #[test]
 fn test_bbox_sphere() {
+    use testing::*;
+
     let (_, bbox) = gen_test_spheres();
     assert_eq!(bbox.bmin, Vec3::new(0.0, 0.0, 0.0));
     assert_eq!(bbox.bmax, Vec3::new(11.0, 5.0, 5.0));
@@ -186,39 +279,82 @@ fn test_bbox_sphere() {
 
 #[test]
 fn test_split_sphere() {
+    use testing::*;
+
     let (spheres, bbox) = gen_test_spheres();
-    let ((_, l), (_, r)) = split(bbox, &vec![0, 1], &spheres);
+    let ((_, l), (_, r)) = split(bbox, &vec![0, 1], &spheres, &KDTreeSettings::median()).unwrap();
     assert_eq!(l.len(), 1);
     assert_eq!(r.len(), 1);
 }
 
-fn build_node(bbox : BBox, indcs : Vec<i32>, objects : &Vec<BoxedObject>) -> KDTreeNode {
-    const MAX_INDCS : usize = 30;
+#[test]
+fn test_occluded_spheres() {
+    use testing::*;
+
+    let (spheres, _) = gen_test_spheres();
+    let tree = build_kd_tree_with(spheres, &KDTreeSettings { max_leaf_size : 0, .. KDTreeSettings::median() });
+    // off the median split planes, a ray lying in one of them misses both boxes
+    let ray = Ray::new(Vec3::new(-10.0, 2.2, 2.7), Vec3::new(1.0, 0.0, 0.0));
+    let (t, _) = tree.intersect(ray).unwrap();
+    assert!(tree.occluded(ray, t + 0.01));
+    assert!(!tree.occluded(ray, t - 0.01));
+    let away = Ray::new(ray.pos, Vec3::new(-1.0, 0.0, 0.0));
+    assert!(!tree.occluded(away, 100.0));
+}
+
+#[test]
+fn test_split_sah() {
+    use testing::*;
+
+    let (spheres, bbox) = gen_test_spheres();
+    let settings = KDTreeSettings { intersect_cost : 1000.0, .. KDTreeSettings::default() };
+    let ((bl, l), (br, r)) = split_sah(bbox, &vec![0, 1], &spheres, &settings).unwrap();
+    assert_eq!(l, vec![0]);
+    assert_eq!(r, vec![1]);
+    assert_eq!(bl.bmax.x, 5.0);
+    assert_eq!(br.bmin.x, 5.0);
+
+    // splitting is never worth it if traversal is that expensive
+    let settings = KDTreeSettings { traversal_cost : 1000.0, .. KDTreeSettings::default() };
+    assert!(split_sah(bbox, &vec![0, 1], &spheres, &settings).is_none());
+}
 
-    if indcs.len() <  MAX_INDCS {
-        KDTreeNode {
+fn build_node(bbox : BBox, indcs : Vec<i32>, objects : &Vec<BoxedObject>,
+              settings : &KDTreeSettings, depth : usize) -> KDTreeNode {
+    let children = if indcs.len() <= settings.max_leaf_size || depth >= settings.max_depth {
+        None
+    } else {
+        split(bbox, &indcs, objects, settings)
+    };
+
+    match children {
+        None => KDTreeNode {
             bbox    : bbox,
             indices : indcs,
             left    : None,
             right   : None
-        }
-    } else {
-        let ((bl, l), (br, r)) = split(bbox, &indcs, objects);
-        KDTreeNode {
+        },
+        Some(((bl, l), (br, r))) => KDTreeNode {
             bbox    : bbox,
             indices : indcs,
-            left    : Some(Box::new(build_node(bl, l, objects))),
-            right   : Some(Box::new(build_node(br, r, objects)))
+            left    : Some(Box::new(build_node(bl, l, objects, settings, depth + 1))),
+            right   : Some(Box::new(build_node(br, r, objects, settings, depth + 1)))
         }
     }
 }
 
 pub fn build_kd_tree(objects: Vec<BoxedObject>) -> KDTreeRoot {
+    build_kd_tree_with(objects, &KDTreeSettings::default())
+}
+
+pub fn build_kd_tree_with(objects: Vec<BoxedObject>, settings : &KDTreeSettings) -> KDTreeRoot {
     KDTreeRoot {
         tree    : Box::new(build_node(
             objects.get_bound_box(),
             (0 .. objects.len() as i32).collect(),
-            &objects)),
+            &objects,
+            settings,
+            0)),
         objects : objects
 
     }
@@ -226,6 +362,9 @@ pub fn build_kd_tree(objects: Vec<BoxedObject>) -> KDTreeRoot {
 
 #[test]
 fn test_kd_three_cow() {
+    use objects::mesh::*;
+    use materials::*;
+
     let cow = load_mesh("models/chair/chair.obj",
                         Vec3::new(0.0, 9.0, 0.0),
                         Vec3::new(2.5, 2.5, 2.5),
@@ -241,3 +380,34 @@ fn test_kd_three_cow() {
     assert!(bbox.bmin.z < bbox.bmax.z);
 }
 
+
+#[test]
+fn test_kd_three_sah_cow() {
+    use objects::mesh::*;
+    use materials::*;
+
+    let load = || load_mesh("cow.obj",
+                            Vec3::new(0.0, 9.0, 0.0),
+                            Vec3::new(2.5, 2.5, 2.5),
+                            &Rot3::new_identity(3),
+                            test_mat()).unwrap();
+    let median = build_kd_tree_with(load(), &KDTreeSettings::median());
+    let sah = build_kd_tree_with(load(), &KDTreeSettings::default());
+
+    let bbox = sah.tree.bbox;
+    let center = (bbox.bmin + bbox.bmax) * 0.5;
+    let mut median_tests = 0;
+    let mut sah_tests = 0;
+    for i in 0 .. 32 {
+        for j in 0 .. 32 {
+            let target = Vec3::new(bbox.bmin.x + (bbox.bmax.x - bbox.bmin.x) * i as f32 / 31.0,
+                                   bbox.bmin.y + (bbox.bmax.y - bbox.bmin.y) * j as f32 / 31.0,
+                                   center.z);
+            let pos = Vec3::new(target.x, target.y, bbox.bmin.z - 10.0);
+            let ray = Ray::new(pos, (target - pos).normalize());
+            median_tests += median.count_tests(ray);
+            sah_tests += sah.count_tests(ray);
+        }
+    }
+    assert!(sah_tests < median_tests);
+}
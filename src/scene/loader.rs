@@ -0,0 +1,554 @@
+use std::fs::File;
+use std::io::Read;
+
+use rustc_serialize::json::*;
+
+use core::*;
+use camera::*;
+use light::*;
+use envmap::*;
+use emitter::*;
+use surface::*;
+use texture::*;
+use principled::*;
+use objects::sphere::*;
+use objects::plane::*;
+use objects::mesh::*;
+use materials::*;
+use scene::*;
+use scene::kdtree::*;
+use scene::bvh::*;
+use scene::toplevel::*;
+
+use std::sync::Arc;
+use std::f32::consts::PI;
+
+/// Json value together with its location in the scene file,
+/// so every error can tell which entry is wrong.
+struct Entry<'a> {
+    json : &'a Json,
+    path : String
+}
+
+impl<'a> Entry<'a> {
+    fn error<T>(&self, msg : &str) -> Result<T, String> {
+        Err(format!("{}: {}", self.path, msg))
+    }
+
+    fn find(&self, name : &str) -> Option<Entry<'a>> {
+        self.json.find(name).map(|json| Entry {
+            json : json,
+            path : format!("{}.{}", self.path, name)
+        })
+    }
+
+    fn field(&self, name : &str) -> Result<Entry<'a>, String> {
+        match self.json.as_object() {
+            None => self.error("object expected"),
+            Some(_) => match self.find(name) {
+                Some(entry) => Ok(entry),
+                None => self.error(&format!("missing field `{}`", name))
+            }
+        }
+    }
+
+    fn items(&self) -> Result<Vec<Entry<'a>>, String> {
+        match self.json.as_array() {
+            Some(array) => Ok(array.iter().enumerate().map(|(i, json)| Entry {
+                json : json,
+                path : format!("{}[{}]", self.path, i)
+            }).collect()),
+            None => self.error("array expected")
+        }
+    }
+
+    fn f32(&self) -> Result<f32, String> {
+        match self.json.as_f64() {
+            Some(x) => Ok(x as f32),
+            None => self.error("number expected")
+        }
+    }
+
+    fn bool(&self) -> Result<bool, String> {
+        match self.json.as_boolean() {
+            Some(b) => Ok(b),
+            None => self.error("boolean expected")
+        }
+    }
+
+    fn string(&self) -> Result<&'a str, String> {
+        match self.json.as_string() {
+            Some(s) => Ok(s),
+            None => self.error("string expected")
+        }
+    }
+
+    fn vec3(&self) -> Result<Vec3<f32>, String> {
+        let items = try!(self.items());
+        if items.len() != 3 {
+            return self.error("array of 3 numbers expected");
+        }
+        Ok(Vec3::new(try!(items[0].f32()), try!(items[1].f32()), try!(items[2].f32())))
+    }
+
+    fn vec2(&self) -> Result<Vec2<f32>, String> {
+        let items = try!(self.items());
+        if items.len() != 2 {
+            return self.error("array of 2 numbers expected");
+        }
+        Ok(Vec2::new(try!(items[0].f32()), try!(items[1].f32())))
+    }
+
+    fn f32_or(&self, name : &str, default : f32) -> Result<f32, String> {
+        match self.find(name) {
+            Some(entry) => entry.f32(),
+            None => Ok(default)
+        }
+    }
+
+    fn vec3_or(&self, name : &str, default : Vec3<f32>) -> Result<Vec3<f32>, String> {
+        match self.find(name) {
+            Some(entry) => entry.vec3(),
+            None => Ok(default)
+        }
+    }
+
+    fn kind(&self) -> Result<&'a str, String> {
+        self.field("type").and_then(|t| t.string())
+    }
+}
+
+fn load_camera(entry : &Entry, w : i32, h : i32) -> Result<Camera, String> {
+    let pos = try!(try!(entry.field("pos")).vec3());
+    let look_at = try!(try!(entry.field("look_at")).vec3());
+    let projection = match entry.find("projection") {
+        None => Projection::Perspective,
+        Some(p) => match try!(p.string()) {
+            "perspective"     => Projection::Perspective,
+            "orthographic"    => Projection::Orthographic(try!(try!(entry.field("height")).f32())),
+            "fisheye"         => Projection::Fisheye,
+            "equirectangular" => Projection::Equirectangular,
+            other => return p.error(&format!("unknown projection `{}`", other))
+        }
+    };
+    Ok(Camera {
+        pos        : pos,
+        look_at    : look_at,
+        up         : try!(entry.vec3_or("up", Vec3::new(0.0, 1.0, 0.0))),
+        fov        : try!(entry.f32_or("fov", 60.0)),
+        w          : w,
+        h          : h,
+        aperture   : try!(entry.f32_or("aperture", 0.0)),
+        focus_dist : try!(entry.f32_or("focus_dist", (look_at - pos).norm())),
+        projection : projection
+    })
+}
+
+/// Light colour scaled by "intensity", or by "power" turned into the intensity
+/// of the light, so lights of the same power give off the same light whatever their shape.
+fn load_light_color(entry : &Entry) -> Result<Vec3<f32>, String> {
+    let color = try!(entry.vec3_or("color", Vec3::new(1.0, 1.0, 1.0)));
+    let power = match entry.find("power") {
+        Some(power) => power,
+        None => return Ok(color * try!(entry.f32_or("intensity", 1.0)))
+    };
+    // solid angle the power is spread over
+    let spread = match try!(entry.kind()) {
+        // only the outer cone, the soft edge is not accounted for
+        "spot" => 2.0 * PI * (1.0 - try!(entry.f32_or("outer", 30.0)).to_radians().cos()),
+        // the colour is the intensity straight in front, falling off with the cosine:
+        // a side gives off PI times that, and both sides glow
+        "disk" | "rect" => 2.0 * PI,
+        "directional" => return power.error("directional lights have no power, use intensity"),
+        // point and sphere lights shine the same all around
+        _ => 4.0 * PI
+    };
+    Ok(color * (try!(power.f32()) / spread))
+}
+
+fn load_light(entry : &Entry) -> Result<Light, String> {
+    let color = try!(load_light_color(entry));
+    match try!(entry.kind()) {
+        "point" => Ok(Light::PointLight(
+            try!(try!(entry.field("pos")).vec3()),
+            color)),
+        "sphere" => Ok(Light::SphereLight(
+            try!(try!(entry.field("pos")).vec3()),
+            try!(try!(entry.field("radius")).f32()),
+            color)),
+        "disk" => Ok(Light::DiskLight(
+            try!(try!(entry.field("pos")).vec3()),
+            try!(try!(entry.field("normal")).vec3()).normalize(),
+            try!(try!(entry.field("radius")).f32()),
+            color)),
+        // centered at `pos`, spanned by the `u` and `v` edges
+        "rect" => Ok(Light::RectLight(
+            try!(try!(entry.field("pos")).vec3()),
+            try!(try!(entry.field("u")).vec3()),
+            try!(try!(entry.field("v")).vec3()),
+            color)),
+        // cone angles are half-angles in degrees, like the camera fov
+        "spot" => Ok(Light::SpotLight(
+            try!(try!(entry.field("pos")).vec3()),
+            try!(try!(entry.field("dir")).vec3()).normalize(),
+            try!(entry.f32_or("inner", 20.0)).to_radians(),
+            try!(entry.f32_or("outer", 30.0)).to_radians(),
+            color)),
+        // the sun is about half a degree across
+        "directional" => Ok(Light::DirectionalLight(
+            try!(try!(entry.field("dir")).vec3()).normalize(),
+            try!(entry.f32_or("diameter", 0.0)).to_radians(),
+            color)),
+        other => entry.error(&format!("unknown light type `{}`", other))
+    }
+}
+
+/// Equirectangular `.hdr` image, turned by "rotation" degrees around the up axis.
+fn load_env(entry : &Entry) -> Result<EnvMap, String> {
+    let path = try!(try!(entry.field("path")).string());
+    match EnvMap::load(path,
+                       try!(entry.f32_or("intensity", 1.0)),
+                       try!(entry.f32_or("rotation", 0.0)).to_radians()) {
+        Ok(env) => Ok(env),
+        Err(e) => entry.error(&format!("can't load environment `{}`: {}", path, e))
+    }
+}
+
+fn load_material(entry : &Entry) -> Result<BoxedMaterial, String> {
+    match try!(entry.kind()) {
+        "solid" => Ok(Box::new(SolidColorMat {
+            color : try!(try!(entry.field("color")).vec3())
+        })),
+        "chess" => Ok(Box::new(ChessMat {
+            sx : try!(entry.f32_or("sx", 1.0)),
+            sy : try!(entry.f32_or("sy", 1.0))
+        })),
+        other => entry.error(&format!("unknown material type `{}`", other))
+    }
+}
+
+/// Placement and filtering shared by all the textures of a material: "uv_scale" is a number
+/// or a [u, v] pair, "uv_rotation" is in degrees, "filter" is trilinear unless told otherwise.
+fn load_mapping(entry : &Entry) -> Result<Mapping, String> {
+    let mut mapping = Mapping::new();
+    if let Some(scale) = entry.find("uv_scale") {
+        mapping.scale = if scale.json.is_number() {
+            let s = try!(scale.f32());
+            Vec2::new(s, s)
+        } else {
+            try!(scale.vec2())
+        };
+    }
+    if let Some(offset) = entry.find("uv_offset") {
+        mapping.offset = try!(offset.vec2());
+    }
+    mapping.rotation = try!(entry.f32_or("uv_rotation", 0.0)).to_radians();
+    if let Some(wrap) = entry.find("wrap") {
+        mapping.wrap = match Wrap::from_name(try!(wrap.string())) {
+            Some(w) => w,
+            None => return wrap.error("repeat, clamp or mirror expected")
+        };
+    }
+    if let Some(filter) = entry.find("filter") {
+        mapping.filter = match TextureFilter::from_name(try!(filter.string())) {
+            Some(f) => f,
+            None => return filter.error("nearest, bilinear or trilinear expected")
+        };
+    }
+    Ok(mapping)
+}
+
+/// Number, colour, or the path of a texture to read the parameter from.
+fn load_mapped(entry : &Entry, name : &str, default : f32, mapping : Mapping,
+               textures : &mut TextureCache) -> Result<Mapped, String> {
+    let value = match entry.find(name) {
+        Some(value) => value,
+        None => return Ok(Mapped::Value(Vec3::new(default, default, default)))
+    };
+    if value.json.is_string() {
+        let path = try!(value.string());
+        match textures.load(path) {
+            Ok(texture) => Ok(Mapped::Texture(texture, mapping)),
+            Err(e) => value.error(&format!("can't load texture `{}`: {}", path, e))
+        }
+    } else if value.json.is_number() {
+        let x = try!(value.f32());
+        Ok(Mapped::Value(Vec3::new(x, x, x)))
+    } else {
+        Ok(Mapped::Value(try!(value.vec3())))
+    }
+}
+
+/// Surface overriding the materials of the object, if its material needs one.
+fn load_surface(entry : &Entry, textures : &mut TextureCache) -> Result<Option<Surface>, String> {
+    match try!(entry.kind()) {
+        "glass" => Ok(Some(Surface::Dielectric {
+            ior  : try!(entry.f32_or("ior", 1.5)),
+            tint : try!(entry.vec3_or("tint", Vec3::new(1.0, 1.0, 1.0)))
+        })),
+        // the area is known once the object is loaded
+        "emissive" => Ok(Some(Surface::Emissive {
+            radiance : try!(entry.vec3_or("color", Vec3::new(1.0, 1.0, 1.0))) *
+                try!(entry.f32_or("intensity", 1.0)),
+            area     : 0.0
+        })),
+        "metal" => {
+            let preset = match entry.find("preset") {
+                None => None,
+                Some(p) => match try!(p.string()) {
+                    "gold"      => Some(gold()),
+                    "copper"    => Some(copper()),
+                    "aluminium" => Some(aluminium()),
+                    other => return p.error(&format!("unknown metal `{}`", other))
+                }
+            };
+            let (eta, k) = match preset {
+                Some(Surface::Conductor { eta, k, .. }) => (eta, k),
+                _ => (try!(try!(entry.field("eta")).vec3()), try!(try!(entry.field("k")).vec3()))
+            };
+            Ok(Some(Surface::Conductor {
+                roughness : try!(entry.f32_or("roughness", 0.2)),
+                eta       : eta,
+                k         : k
+            }))
+        }
+        "plastic" => Ok(Some(Surface::Plastic {
+            roughness : try!(entry.f32_or("roughness", 0.3)),
+            ior       : try!(entry.f32_or("ior", 1.5)),
+            color     : try!(try!(entry.field("color")).vec3())
+        })),
+        "texture" => {
+            let field = try!(entry.field("path"));
+            let path = try!(field.string());
+            let mut mapping = try!(load_mapping(entry));
+            // uv repeats per unit of the older `scale`, on top of `uv_scale`
+            mapping.scale = mapping.scale * try!(entry.f32_or("scale", 1.0));
+            match textures.load(path) {
+                Ok(texture) => Ok(Some(Surface::Textured(Mapped::Texture(texture, mapping)))),
+                Err(e) => field.error(&format!("can't load texture `{}`: {}", path, e))
+            }
+        }
+        "principled" => {
+            let mapping = try!(load_mapping(entry));
+            Ok(Some(Surface::Principled(Arc::new(Principled {
+                color        : try!(load_mapped(entry, "color", 0.8, mapping, textures)),
+                metallic     : try!(load_mapped(entry, "metallic", 0.0, mapping, textures)),
+                roughness    : try!(load_mapped(entry, "roughness", 0.5, mapping, textures)),
+                specular     : try!(load_mapped(entry, "specular", 0.5, mapping, textures)),
+                sheen        : try!(load_mapped(entry, "sheen", 0.0, mapping, textures)),
+                clearcoat    : try!(load_mapped(entry, "clearcoat", 0.0, mapping, textures)),
+                transmission : try!(load_mapped(entry, "transmission", 0.0, mapping, textures)),
+                ior          : try!(entry.f32_or("ior", 1.5))
+            }))))
+        }
+        _ => Ok(None)
+    }
+}
+
+/// Scene object of the entry shaded by `surface`, with its shape for sampling it as a light if it glows.
+fn load_object(entry : &Entry, surface : Option<&Surface>) -> Result<(BoxedObject, Option<Emitter>), String> {
+    let material = match surface {
+        Some(_) => surface_material(),
+        None => try!(load_material(&try!(entry.field("material"))))
+    };
+    let emissive = match surface {
+        Some(&Surface::Emissive { .. }) => true,
+        _ => false
+    };
+    match try!(entry.kind()) {
+        "sphere" => {
+            let pos = try!(try!(entry.field("pos")).vec3());
+            let radius = try!(try!(entry.field("radius")).f32());
+            Ok((Box::new(Sphere {
+                pos      : pos,
+                radius   : radius,
+                material : material
+            }), if emissive { Some(Emitter::Sphere(pos, radius)) } else { None }))
+        }
+        "plane" if emissive => entry.error("plane can't be emissive, use a rect light instead"),
+        "plane" => Ok((Box::new(Plane {
+            pos      : try!(entry.vec3_or("pos", Vec3::new(0.0, 0.0, 0.0))),
+            normal   : try!(try!(entry.field("normal")).vec3()).normalize(),
+            material : material
+        }), None)),
+        "mesh" => {
+            let path = try!(try!(entry.field("path")).string());
+            let pos = try!(entry.vec3_or("pos", Vec3::new(0.0, 0.0, 0.0)));
+            let scale = try!(entry.vec3_or("scale", Vec3::new(1.0, 1.0, 1.0)));
+            // rotation is an axis-angle vector, like `Rot3::new` takes
+            let rot = Rot3::new(try!(entry.vec3_or("rot", Vec3::new(0.0, 0.0, 0.0))));
+            let mesh = match load_mesh(path, pos, scale, &rot, material) {
+                Ok(mesh) => mesh,
+                Err(_) => return entry.error(&format!("can't load mesh `{}`", path))
+            };
+            // the loaded triangles only answer ray queries, the emitter reads its own copy
+            let emitter = if emissive {
+                match Emitter::load_mesh(path, pos, scale, &rot) {
+                    Ok(emitter) => Some(emitter),
+                    Err(e) => return entry.error(&format!("can't load mesh `{}`: {}", path, e))
+                }
+            } else {
+                None
+            };
+            let object : BoxedObject = match entry.find("accel") {
+                None => Box::new(build_kd_tree(mesh)),
+                Some(accel) => match try!(accel.string()) {
+                    "kdtree" => Box::new(build_kd_tree(mesh)),
+                    "bvh"    => Box::new(build_bvh(mesh)),
+                    other => return accel.error(&format!("unknown acceleration structure `{}`", other))
+                }
+            };
+            Ok((object, emitter))
+        }
+        other => entry.error(&format!("unknown object type `{}`", other))
+    }
+}
+
+/// Builds a scene from its json description, see `scenes/` for examples.
+pub fn parse_scene(text : &str, w : i32, h : i32) -> Result<Scene, String> {
+    let json = match Json::from_str(text) {
+        Ok(json) => json,
+        Err(ParserError::SyntaxError(code, line, col)) =>
+            return Err(format!("{}:{}: {}", line, col, error_str(code))),
+        Err(e) => return Err(format!("{:?}", e))
+    };
+    let root = Entry { json : &json, path : "scene".to_string() };
+
+    let cam = try!(load_camera(&try!(root.field("camera")), w, h));
+    let mut lights = Vec::new();
+    for entry in try!(try!(root.field("lights")).items()) {
+        lights.push(try!(load_light(&entry)));
+    }
+    let mut bounded = Vec::new();
+    let mut unbounded = Vec::new();
+    let mut textures = TextureCache::new();
+    for entry in try!(try!(root.field("objects")).items()) {
+        let mut surface = try!(load_surface(&try!(entry.field("material")), &mut textures));
+        let (object, emitter) = try!(load_object(&entry, surface.as_ref()));
+        if let (Some(emitter), Some(&mut Surface::Emissive { radiance, ref mut area })) = (emitter, surface.as_mut()) {
+            *area = emitter.area();
+            lights.push(Light::Emissive(emitter, radiance));
+        }
+        if try!(entry.kind()) == "plane" {
+            unbounded.push((object, surface));
+        } else {
+            bounded.push((object, surface));
+        }
+    }
+    let objects = TopLevel::with_surfaces(bounded, unbounded);
+    // scenes made before the falloff turn it off to keep their look
+    let falloff = match root.find("falloff") {
+        Some(falloff) => try!(falloff.bool()),
+        None => true
+    };
+    let env = match root.find("environment") {
+        Some(entry) => Some(try!(load_env(&entry))),
+        None => None
+    };
+    Ok(Scene {
+        cam      : cam,
+        objects  : objects,
+        lights   : lights,
+        falloff  : falloff,
+        env      : env,
+        textures : textures
+    })
+}
+
+pub fn load_scene(path : &str, w : i32, h : i32) -> Result<Scene, String> {
+    let mut text = String::new();
+    match File::open(path).and_then(|mut f| f.read_to_string(&mut text)) {
+        Ok(_) => parse_scene(&text, w, h).map_err(|e| format!("{}: {}", path, e)),
+        Err(e) => Err(format!("{}: {}", path, e))
+    }
+}
+
+#[test]
+fn test_parse_scene() {
+    let scene = parse_scene(r#"{
+        "camera"  : { "pos" : [-10, 8, -3], "look_at" : [0, 0, 10], "fov" : 45 },
+        "falloff" : false,
+        "lights"  : [ { "type" : "point", "pos" : [-15, 10, 4], "power" : 400 },
+                      { "type" : "rect", "pos" : [0, 10, 0], "u" : [2, 0, 0], "v" : [0, 0, 2] },
+                      { "type" : "directional", "dir" : [1, -1, 0], "diameter" : 0.5 } ],
+        "objects" : [
+            { "type" : "sphere", "pos" : [-4, 1, 2], "radius" : 1,
+              "material" : { "type" : "solid", "color" : [1, 0, 0] } },
+            { "type" : "plane", "normal" : [0, 1, 0],
+              "material" : { "type" : "chess", "sx" : 0.5, "sy" : 0.5 } },
+            { "type" : "sphere", "pos" : [-2, 1, 2], "radius" : 1,
+              "material" : { "type" : "glass", "ior" : 1.33 } },
+            { "type" : "sphere", "pos" : [4, 1, 2], "radius" : 1,
+              "material" : { "type" : "metal", "preset" : "gold", "roughness" : 0.1 } },
+            { "type" : "sphere", "pos" : [2, 5, 2], "radius" : 0.5,
+              "material" : { "type" : "emissive", "color" : [1, 0.8, 0.6], "intensity" : 10 } }
+        ]
+    }"#, 400, 300).unwrap();
+    assert_eq!(scene.cam.fov, 45.0);
+    assert_eq!(scene.cam.w, 400);
+    assert_eq!(scene.lights.len(), 4);
+    assert!(!scene.falloff);
+    assert!((scene.lights[0].color().x - 400.0 / (4.0 * PI)).abs() < 0.0001);
+    assert_eq!(scene.objects.len(), 5);
+
+    let err = parse_scene(r#"{
+        "camera"  : { "pos" : [0, 0, 0], "look_at" : [0, 0, 1] },
+        "lights"  : [],
+        "objects" : [
+            { "type" : "sphere", "pos" : [0, 1, 2], "radius" : 1,
+              "material" : { "type" : "solid", "color" : [1, 0] } }
+        ]
+    }"#, 400, 300).err().unwrap();
+    assert_eq!(err, "scene.objects[0].material.color: array of 3 numbers expected");
+}
+
+#[test]
+fn test_light_power() {
+    let json = Json::from_str(r#"[
+        { "type" : "point", "pos" : [0, 0, 0], "power" : 100 },
+        { "type" : "spot", "pos" : [0, 0, 0], "dir" : [0, -1, 0], "outer" : 60, "power" : 100 },
+        { "type" : "rect", "pos" : [0, 0, 0], "u" : [1, 0, 0], "v" : [0, 0, 1], "power" : 100 },
+        { "type" : "directional", "dir" : [0, -1, 0], "power" : 100 }
+    ]"#).unwrap();
+    let lights = Entry { json : &json, path : "lights".to_string() }.items().unwrap();
+    let color = |i : usize| load_light(&lights[i]).unwrap().color().x;
+    assert!((color(0) - 100.0 / (4.0 * PI)).abs() < 0.0001);
+    // a 60 degree cone covers a quarter of the sphere
+    assert!((color(1) - 100.0 / PI).abs() < 0.0001);
+    assert!((color(2) - 100.0 / (2.0 * PI)).abs() < 0.0001);
+    assert!(load_light(&lights[3]).is_err());
+}
+
+#[test]
+fn test_emissive_mesh() {
+    use std::env;
+    use std::io::Write;
+
+    // a 2x2 square lamp, made bigger by the scale
+    let path = env::temp_dir().join("rays_test_lamp.obj");
+    File::create(&path).and_then(|mut f| f.write_all(b"v 0 0 0\nv 1 0 0\nv 1 0 1\nv 0 0 1\nf 1 2 3 4\n")).unwrap();
+    let scene = parse_scene(&format!(r#"{{
+        "camera"  : {{ "pos" : [0, 5, -10], "look_at" : [0, 0, 0] }},
+        "lights"  : [],
+        "objects" : [
+            {{ "type" : "mesh", "path" : "{}", "pos" : [0, 4, 0], "scale" : [2, 2, 2],
+               "material" : {{ "type" : "emissive", "intensity" : 5 }} }}
+        ]
+    }}"#, path.display()), 40, 30).unwrap();
+    assert_eq!(scene.lights.len(), 1);
+    match scene.lights[0] {
+        Light::Emissive(ref emitter, radiance) => {
+            assert!((emitter.area() - 4.0).abs() < 0.0001);
+            assert_eq!(radiance, Vec3::new(5.0, 5.0, 5.0));
+            let (p, _) = emitter.sample(Vec2::new(0.3, 0.6));
+            assert!((p.y - 4.0).abs() < 0.0001);
+        }
+        _ => panic!("the mesh should be an emissive light")
+    }
+    // the area of the emitter reaches the surface too, for weighting hits on it
+    let hit = scene.objects.intersect_surface(Ray::new(Vec3::new(1.0, 0.0, 1.0), Vec3::new(0.0, 1.0, 0.0))).unwrap();
+    match hit.surface {
+        Some(&Surface::Emissive { area, .. }) => assert!((area - 4.0).abs() < 0.0001),
+        _ => panic!("the mesh should be shaded as emissive")
+    }
+}
@@ -1,36 +1,54 @@
 pub mod bbox;
 pub mod kdtree;
+pub mod bvh;
+pub mod toplevel;
+pub mod loader;
 
 use core::*;
 use camera::*;
 use light::*;
+use envmap::*;
+use texture::*;
+use surface::*;
 use objects::sphere::*;
 use objects::plane::*;
 use objects::mesh::*;
 use materials::*;
 use scene::kdtree::*;
+use scene::toplevel::*;
 
 pub struct Scene {
-    pub cam     : Camera,
-    pub objects : Vec<BoxedObject>,
-    pub lights  : Vec<Light>
+    pub cam      : Camera,
+    pub objects  : TopLevel,
+    pub lights   : Vec<Light>,
+    /// Inverse-square falloff of the light with the distance, off for the legacy scenes.
+    pub falloff  : bool,
+    /// Light and background from every direction rays escape to.
+    pub env      : Option<EnvMap>,
+    /// Images the materials read from, each loaded once.
+    pub textures : TextureCache
 }
 
+/// Hit inside the ray extent. The object is given the ray shifted to `t_min`,
+/// so it doesn't have to know about the extent itself.
+fn obj_intersect(obj : &BoxedObject, r : Ray) -> Option<(f32, IntersectData)> {
+    let shifted = r.shifted();
+    match (*obj).intersect(shifted) {
+        Some((t, data)) if shifted.contains(t) => Some((t + r.t_min, data)),
+        _ => None
+    }
+}
+
+/// Closer of the `min` and the `obj` hit. Only hits inside the ray extent count,
+/// and `obj` is asked only for hits closer than `min`.
 fn obj_min(min : Option<(f32, IntersectData)>, obj : &BoxedObject, r : Ray) -> Option<(f32, IntersectData)>{
-    let cur = (*obj).intersect(r);
-    match min {
-        None => cur,
-        Some((min_distance, _)) => {
-            match cur {
-                None => min,
-                Some(cur_distance) =>
-                    if cur_distance.0 > min_distance {
-                        min
-                    } else {
-                        Some(cur_distance)
-                    }
-            }
-        }
+    let r = match min {
+        Some((min_distance, _)) => r.clip(min_distance),
+        None => r
+    };
+    match obj_intersect(obj, r) {
+        cur @ Some(_) => cur,
+        None => min
     }
 }
 
@@ -39,6 +57,9 @@ impl Intersectable for Vec<BoxedObject> {
         let iter = self.iter();
         iter.fold(None, |acc, cur| { obj_min(acc, cur, r) })
     }
+    fn occluded(&self, r : Ray, t_max : f32) -> bool {
+        self.iter().any(|obj| obj.occluded(r, t_max))
+    }
 }
 
 impl<'a> Intersectable for Vec<&'a BoxedObject> {
@@ -46,6 +67,9 @@ impl<'a> Intersectable for Vec<&'a BoxedObject> {
         let iter = self.iter();
         iter.fold(None, |acc, cur| { obj_min(acc, *cur, r) })
     }
+    fn occluded(&self, r : Ray, t_max : f32) -> bool {
+        self.iter().any(|obj| obj.occluded(r, t_max))
+    }
 }
 
 
@@ -57,7 +81,10 @@ pub fn some_cow_scene(w : i32, h : i32) -> Scene {
         up      : Vec3::new(0.0, 1.0, 0.0),
         fov     : 60.0,
         w       : w,
-        h       : h
+        h       : h,
+        aperture   : 0.0,
+        focus_dist : 10.0,
+        projection : Projection::Perspective
     };
     let cow = load_mesh("cow.obj",
                         Vec3::new(0.0, 9.0, 0.0),
@@ -85,20 +112,24 @@ pub fn some_cow_scene(w : i32, h : i32) -> Scene {
                      radius   : 8.0,
                      material : Box::new(SolidColorMat { color : Vec3::new(0.0, 0.0, 1.0) })
                  }),
+        Box::new(build_kd_tree(cow))
+            ];
+    let planes = vec![
         Box::new(Plane
                  {
                      pos    : Vec3::new(0.0, 0.0, 0.0),
                      normal : Vec3::new(0.0, 1.0, 0.0),
                      material : Box::new(ChessMat{sx : 0.5, sy : 0.5})
-                 }),
-        Box::new(build_kd_tree(cow))
-            ];
+                 }) as BoxedObject];
     Scene {
         cam : camera,
-        objects : objects,
+        objects : TopLevel::new(objects, planes),
         lights : vec![Light::PointLight(Vec3::new(-10.0, 3.0, 10.0), Vec3::new(0.6, 0.0, 0.0)),
                       Light::PointLight(Vec3::new(-10.0, 3.0, -10.0), Vec3::new(0.0, 0.0, 0.6)),
-                      Light::PointLight(Vec3::new(-10.0, 3.0, 0.0), Vec3::new(0.0, 0.6, 0.0))]}
+                      Light::PointLight(Vec3::new(-10.0, 3.0, 0.0), Vec3::new(0.0, 0.6, 0.0))],
+        falloff  : false,
+        env      : None,
+        textures : TextureCache::new()}
 }
 
 #[allow(dead_code)]
@@ -109,38 +140,48 @@ pub fn some_room_scene(w : i32, h : i32) -> Scene {
         up      : Vec3::new(0.0, 1.0, 0.0),
         fov     : 60.0,
         w       : w,
-        h       : h
+        h       : h,
+        aperture   : 0.0,
+        focus_dist : 10.0,
+        projection : Projection::Perspective
     };
 
+    // filtered and mipmapped, the floor pattern aliases into moire far away otherwise,
+    // and read once from the scene cache however many hits need it
+    let mut textures = TextureCache::new();
+    let floor = Mapped::Texture(textures.load("models/lin.jpg").unwrap(),
+                                Mapping { scale : Vec2::new(0.2, 0.2), .. Mapping::new() });
+    let chair = Mapped::Texture(textures.load("models/chair/chair_d.png").unwrap(),
+                                Mapping { scale : Vec2::new(0.01, 0.01), .. Mapping::new() });
+    let planes = vec![
+        (Box::new(Plane
+                  {
+                      pos      : Vec3::new(0.0, 0.0, 0.0),
+                      normal   : Vec3::new(0.0, 1.0, 0.0),
+                      material : surface_material()
+                  }) as BoxedObject, Some(Surface::Textured(floor)))];
     let objects = vec![
-        Box::new(Plane
-                 {
-                     pos      : Vec3::new(0.0, 0.0, 0.0),
-                     normal   : Vec3::new(0.0, 1.0, 0.0),
-                     material : Box::new(TextureMat::load("models/lin.jpg", 0.2))
-//                      material : Box::new(ChessMat { sx : 0.5, sy : 0.5 })
-                 }) as BoxedObject,
         Box::new(Sphere
                  {
                      pos      : Vec3::new(-4.0, 1.0, 2.0),
                      radius   : 1.0,
                      material : Box::new(SolidColorMat { color : Vec3::new(1.0, 0.0, 0.0) })
-                 }),
-        // Box::new(build_kd_tree(load_mesh("models/holder/holder.obj",
-        //                                  Vec3::new(0.0, 0.0, 17.0),
-        //                                  Vec3::new(0.03, 0.03, 0.03),
-        //                                  &Rot3::new(Vec3::new(0.0, 0.0, 0.0)),
-        //                                  Box::new(TextureMat::load("models/holder/wood.jpg"))).unwrap())),
+                 }) as BoxedObject,
         Box::new(build_kd_tree(load_mesh("models/chair/chair.obj",
                                          Vec3::new(0.0, 0.0, 0.0),
                                          Vec3::new(7.0, 7.0, 7.0),
                                          &Rot3::new(Vec3::new(0.0, 0.0, 0.0)),
-                                         Box::new(TextureMat::load("models/chair/chair_d.png", 0.01))).unwrap())),
+                                         surface_material()).unwrap())),
             ];
+    let surfaces = vec![None, Some(Surface::Textured(chair))];
+    let objects = objects.into_iter().zip(surfaces).collect();
     Scene {
         cam : camera,
-        objects : objects,
-        lights : vec![Light::PointLight(Vec3::new(-15.0, 10.0, 4.0), Vec3::new(1.0, 1.0, 1.0))]}
+        objects : TopLevel::with_surfaces(objects, planes),
+        lights : vec![Light::PointLight(Vec3::new(-15.0, 10.0, 4.0), Vec3::new(1.0, 1.0, 1.0))],
+        falloff  : false,
+        env      : None,
+        textures : textures}
 }
 
 #[allow(dead_code)]
@@ -151,7 +192,10 @@ pub fn some_easy_scene(w : i32, h : i32) -> Scene {
         up      : Vec3::new(1.0, 0.0, 0.0),
         fov     : 60.0,
         w       : w,
-        h       : h
+        h       : h,
+        aperture   : 0.0,
+        focus_dist : 10.0,
+        projection : Projection::Perspective
     };
     let scene = vec![
         Box::new(Sphere
@@ -159,18 +203,22 @@ pub fn some_easy_scene(w : i32, h : i32) -> Scene {
                      pos      : Vec3::new(0.0, 3.0, 0.0),
                      radius   : 3.0,
                      material : Box::new(SolidColorMat { color : Vec3::new(1.0, 0.0, 0.0) })
-                 }) as BoxedObject,
+                 }) as BoxedObject];
+    let planes = vec![
         Box::new(Plane
                  {
                      pos    : Vec3::new(0.0, 0.0, 0.0),
                      normal : Vec3::new(0.0, 1.0, 0.0),
                      material : Box::new(ChessMat { sx : 0.01, sy : 0.01 })
-                 })];
-    Scene {cam : camera, objects : scene, lights : vec![
+                 }) as BoxedObject];
+    Scene {cam : camera, objects : TopLevel::new(scene, planes), lights : vec![
         point_light_white(Vec3::new(50.0, 8.0, 0.0)),
         point_light_white(Vec3::new(-50.0, 8.0, 0.0)),
         point_light_white(Vec3::new(0.0, 8.0, 50.0)),
-        point_light_white(Vec3::new(0.0, 8.0, -50.0))]}
+        point_light_white(Vec3::new(0.0, 8.0, -50.0))],
+        falloff  : false,
+        env      : None,
+        textures : TextureCache::new()}
 }
 
 
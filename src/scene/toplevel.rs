@@ -0,0 +1,119 @@
+use core::*;
+use surface::*;
+
+use scene::obj_intersect;
+use scene::bvh::*;
+
+/// Closest hit in the scene: distance along the ray, hit data, the object hit
+/// and the surface it is shaded by, if any.
+pub struct SurfaceHit<'a> {
+    pub t         : f32,
+    pub intersect : IntersectData,
+    pub object    : &'a BoxedObject,
+    pub surface   : Option<&'a Surface>
+}
+
+/// Two level acceleration structure over the scene objects. Bounded ones
+/// (spheres, meshes with their own kd-tree or BVH) go into a BVH over their
+/// bounds, infinite ones like planes have no bounds and are tested one by one.
+/// Objects shaded by their own `Surface` go in with the rest, the surfaces are
+/// kept aside by object index.
+pub struct TopLevel {
+    bounded            : BVHRoot,
+    bounded_surfaces   : Vec<Option<Surface>>,
+    unbounded          : Vec<BoxedObject>,
+    unbounded_surfaces : Vec<Option<Surface>>
+}
+
+impl TopLevel {
+    pub fn new(bounded : Vec<BoxedObject>, unbounded : Vec<BoxedObject>) -> TopLevel {
+        let none = |objects : Vec<BoxedObject>| objects.into_iter().map(|obj| (obj, None)).collect();
+        TopLevel::with_surfaces(none(bounded), none(unbounded))
+    }
+
+    /// Objects with the surface they are shaded by instead of their materials, if any.
+    pub fn with_surfaces(bounded : Vec<(BoxedObject, Option<Surface>)>,
+                         unbounded : Vec<(BoxedObject, Option<Surface>)>) -> TopLevel {
+        let (bounded, bounded_surfaces) = bounded.into_iter().unzip();
+        let (unbounded, unbounded_surfaces) = unbounded.into_iter().unzip();
+        TopLevel {
+            bounded            : build_bvh(bounded),
+            bounded_surfaces   : bounded_surfaces,
+            unbounded          : unbounded,
+            unbounded_surfaces : unbounded_surfaces
+        }
+    }
+
+    /// Number of top level objects, a mesh counts as one.
+    pub fn len(&self) -> usize {
+        self.bounded_surfaces.len() + self.unbounded.len()
+    }
+
+    /// Closest hit, with the object hit and its surface if it has one.
+    pub fn intersect_surface(&self, r : Ray) -> Option<SurfaceHit> {
+        let bounded = self.bounded.intersect_index(r).map(|(t, data, i, obj)| SurfaceHit {
+            t         : t,
+            intersect : data,
+            object    : obj,
+            surface   : self.bounded_surfaces[i].as_ref()
+        });
+        self.unbounded.iter().zip(self.unbounded_surfaces.iter()).fold(bounded, |acc, (obj, surface)| {
+            let r = match acc {
+                Some(ref hit) => r.clip(hit.t),
+                None => r
+            };
+            match obj_intersect(obj, r) {
+                Some((t, data)) => Some(SurfaceHit { t : t, intersect : data, object : obj, surface : surface.as_ref() }),
+                None => acc
+            }
+        })
+    }
+}
+
+impl Intersectable for TopLevel {
+    fn intersect(&self, r : Ray) -> Option<(f32, IntersectData)> {
+        self.intersect_surface(r).map(|hit| (hit.t, hit.intersect))
+    }
+    fn occluded(&self, r : Ray, t_max : f32) -> bool {
+        self.unbounded.iter().any(|obj| obj.occluded(r, t_max)) ||
+            self.bounded.occluded(r, t_max)
+    }
+}
+
+#[test]
+fn test_top_level() {
+    use objects::plane::*;
+    use materials::*;
+    use testing::*;
+
+    let spheres = (0 .. 10).map(|i| gen_test_sphere(Vec3::new(i as f32 * 5.0, 2.0, 0.0), 1.0)).collect();
+    let planes = vec![Box::new(Plane {
+        pos      : Vec3::new(0.0, 0.0, 0.0),
+        normal   : Vec3::new(0.0, 1.0, 0.0),
+        material : test_mat()
+    }) as BoxedObject];
+    let top = TopLevel::new(spheres, planes);
+    assert_eq!(top.len(), 11);
+
+    let down = Vec3::new(0.0, -1.0, 0.0);
+    let (t, _) = top.intersect(Ray::new(Vec3::new(20.0, 10.0, 0.0), down)).unwrap();
+    assert!(t < 10.0 - 0.1);
+    let (t, _) = top.intersect(Ray::new(Vec3::new(22.5, 10.0, 0.0), down)).unwrap();
+    assert!((t - 10.0).abs() < 0.0001);
+
+    let glass_sphere = |x : f32| (gen_test_sphere(Vec3::new(x, 2.0, 0.0), 1.0), Some(glass()));
+    let plane = Box::new(Plane {
+        pos      : Vec3::new(0.0, 0.0, 0.0),
+        normal   : Vec3::new(0.0, 1.0, 0.0),
+        material : test_mat()
+    }) as BoxedObject;
+    let top = TopLevel::with_surfaces((0 .. 10).map(|i| glass_sphere(i as f32 * 5.0 + 2.5)).collect(),
+                                      vec![(plane, None)]);
+    assert_eq!(top.len(), 11);
+    let hit = top.intersect_surface(Ray::new(Vec3::new(22.5, 10.0, 0.0), down)).unwrap();
+    assert!(hit.t < 10.0 - 0.1);
+    assert_eq!(hit.surface, Some(&glass()));
+    assert!(hit.object.intersect(Ray::new(Vec3::new(22.5, 10.0, 0.0), down)).is_some());
+    let hit = top.intersect_surface(Ray::new(Vec3::new(20.0, 10.0, 0.0), down)).unwrap();
+    assert_eq!(hit.surface, None);
+}
@@ -0,0 +1,66 @@
+use na::*;
+
+use texture::*;
+use principled::*;
+use materials::*;
+
+use std::sync::Arc;
+
+/// Shading model of a whole scene object, used instead of its materials.
+/// Materials only report a colour at the hit, this covers what needs more.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Surface {
+    /// Glass, water: reflects and transmits by Fresnel, `tint` filters transmitted light.
+    Dielectric { ior : f32, tint : Vec3<f32> },
+    /// Glows with `radiance` and reflects nothing; sampled through a `Light::Emissive`
+    /// uniformly over its `area`.
+    Emissive { radiance : Vec3<f32>, area : f32 },
+    /// Rough metal, `eta` and `k` are the complex refraction index per colour channel.
+    Conductor { roughness : f32, eta : Vec3<f32>, k : Vec3<f32> },
+    /// Diffuse `color` under a rough clear coat with refraction index `ior`.
+    Plastic { roughness : f32, ior : f32, color : Vec3<f32> },
+    /// Diffuse with the colour read from a texture, filtered and mipmapped, what the
+    /// `texture` material of the scene files gives.
+    Textured(Mapped),
+    /// Principled material, shared between the hits so cloning stays cheap.
+    Principled(Arc<Principled>)
+}
+
+/// Material slot of an object shaded by its `Surface`. Nothing reads it: for such
+/// objects the surface is the one shading path, the materials only shade the rest.
+pub fn surface_material() -> BoxedMaterial {
+    Box::new(SolidColorMat { color : Vec3::new(0.0, 0.0, 0.0) })
+}
+
+pub fn glass() -> Surface {
+    Surface::Dielectric { ior : 1.5, tint : Vec3::new(1.0, 1.0, 1.0) }
+}
+
+pub fn water() -> Surface {
+    Surface::Dielectric { ior : 1.33, tint : Vec3::new(0.9, 0.95, 1.0) }
+}
+
+/// Metal presets, refraction indices at 650, 550 and 450 nm.
+pub fn gold() -> Surface {
+    Surface::Conductor {
+        roughness : 0.2,
+        eta       : Vec3::new(0.143, 0.374, 1.442),
+        k         : Vec3::new(3.983, 2.385, 1.603)
+    }
+}
+
+pub fn copper() -> Surface {
+    Surface::Conductor {
+        roughness : 0.2,
+        eta       : Vec3::new(0.200, 0.924, 1.102),
+        k         : Vec3::new(3.912, 2.452, 2.142)
+    }
+}
+
+pub fn aluminium() -> Surface {
+    Surface::Conductor {
+        roughness : 0.2,
+        eta       : Vec3::new(1.657, 0.880, 0.521),
+        k         : Vec3::new(9.224, 6.270, 4.837)
+    }
+}
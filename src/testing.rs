@@ -0,0 +1,71 @@
+// Fixtures shared by the tests of the other modules.
+
+use core::*;
+use camera::*;
+use objects::sphere::*;
+use materials::*;
+
+pub fn gen_test_camera(w : i32, h : i32) -> Camera {
+    Camera {
+        pos     : Vec3::new(-10.0, 0.0, 0.0),
+        look_at : Vec3::new(0.0, 0.0, 0.0),
+        up      : Vec3::new(0.0, 1.0, 0.0),
+        fov     : 60.0,
+        w       : w,
+        h       : h,
+        aperture   : 0.0,
+        focus_dist : 10.0,
+        projection : Projection::Perspective
+    }
+}
+
+pub fn gen_test_intersectable() -> IntersectData {
+    IntersectData {
+        normal   : Vec3::new(0.0, 1.0, 0.0),
+        pos      : Vec3::new(0.0, 0.0, 0.0),
+        uv       : Vec2::new(0.0, 0.0),
+        material : test_mat()
+    }
+}
+
+pub fn gen_test_sphere(pos : Vec3<f32>, radius : f32) -> BoxedObject {
+    Box::new(Sphere {
+        pos      : pos,
+        radius   : radius,
+        material : test_mat()
+    })
+}
+
+/// Two overlapping spheres along the x axis, with their bounds.
+pub fn gen_test_spheres() -> (Vec<BoxedObject>, BBox) {
+    let spheres = vec![gen_test_sphere(Vec3::new(2.5, 2.5, 2.5), 5.0),
+                       gen_test_sphere(Vec3::new(8.5, 2.5, 2.5), 5.0)];
+    let bbox = spheres.get_bound_box();
+    (spheres, bbox)
+}
+
+/// 5x5x5 grid of unit spheres 4 apart, starting at the origin.
+pub fn gen_test_sphere_grid() -> Vec<BoxedObject> {
+    let mut spheres = Vec::new();
+    for i in 0 .. 5 {
+        for j in 0 .. 5 {
+            for k in 0 .. 5 {
+                spheres.push(gen_test_sphere(Vec3::new(i as f32 * 4.0, j as f32 * 4.0, k as f32 * 4.0), 1.0));
+            }
+        }
+    }
+    spheres
+}
+
+/// Planes across the x axis, reports only the nearest one in front of the ray.
+pub struct Walls(pub Vec<f32>);
+
+impl Intersectable for Walls {
+    fn intersect(&self, r : Ray) -> Option<(f32, IntersectData)> {
+        self.0.iter()
+            .map(|x| (x - r.pos.x) / r.dir.x)
+            .filter(|t| *t > 0.0)
+            .fold(None, |min : Option<f32>, t| Some(min.map_or(t, |min| min.min(t))))
+            .map(|t| (t, gen_test_intersectable()))
+    }
+}
@@ -0,0 +1,319 @@
+use na::*;
+
+use image;
+
+use std::sync::Arc;
+use std::mem::size_of;
+use std::collections::HashMap;
+
+/// What a lookup outside [0, 1) of the texture coordinates gets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Wrap {
+    Repeat,
+    /// The border pixels stretch out.
+    Clamp,
+    /// Repeats, every other copy flipped so the edges meet.
+    Mirror
+}
+
+/// How the pixels around a lookup are blended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TextureFilter {
+    Nearest,
+    Bilinear,
+    /// Bilinear in the two mip levels closest to the pixel footprint, blended.
+    Trilinear
+}
+
+impl Wrap {
+    pub fn from_name(s : &str) -> Option<Wrap> {
+        match &s.to_lowercase()[..] {
+            "repeat" => Some(Wrap::Repeat),
+            "clamp"  => Some(Wrap::Clamp),
+            "mirror" => Some(Wrap::Mirror),
+            _ => None
+        }
+    }
+
+    /// Pixel index for `i` in a row or column of `n` pixels.
+    fn apply(&self, i : i64, n : i64) -> i64 {
+        match *self {
+            Wrap::Repeat => ((i % n) + n) % n,
+            Wrap::Clamp  => i.max(0).min(n - 1),
+            Wrap::Mirror => {
+                let m = ((i % (2 * n)) + 2 * n) % (2 * n);
+                if m < n { m } else { 2 * n - 1 - m }
+            }
+        }
+    }
+}
+
+impl TextureFilter {
+    pub fn from_name(s : &str) -> Option<TextureFilter> {
+        match &s.to_lowercase()[..] {
+            "nearest"   => Some(TextureFilter::Nearest),
+            "bilinear"  => Some(TextureFilter::Bilinear),
+            "trilinear" => Some(TextureFilter::Trilinear),
+            _ => None
+        }
+    }
+}
+
+/// One resolution of a texture, rows from top to bottom.
+#[derive(Debug, PartialEq)]
+struct Level {
+    w      : usize,
+    h      : usize,
+    pixels : Vec<Vec3<f32>>
+}
+
+impl Level {
+    /// Pixel at column `x` and row `y`, rows counted from the bottom.
+    fn pixel(&self, x : i64, y : i64, wrap : Wrap) -> Vec3<f32> {
+        let x = wrap.apply(x, self.w as i64) as usize;
+        let y = wrap.apply(y, self.h as i64) as usize;
+        self.pixels[(self.h - 1 - y) * self.w + x]
+    }
+
+    fn nearest(&self, uv : Vec2<f32>, wrap : Wrap) -> Vec3<f32> {
+        self.pixel((uv.x * self.w as f32).floor() as i64, (uv.y * self.h as f32).floor() as i64, wrap)
+    }
+
+    fn bilinear(&self, uv : Vec2<f32>, wrap : Wrap) -> Vec3<f32> {
+        // pixel centers are at half coordinates
+        let x = uv.x * self.w as f32 - 0.5;
+        let y = uv.y * self.h as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+        let bottom = self.pixel(x0, y0, wrap) * (1.0 - tx) + self.pixel(x0 + 1, y0, wrap) * tx;
+        let top = self.pixel(x0, y0 + 1, wrap) * (1.0 - tx) + self.pixel(x0 + 1, y0 + 1, wrap) * tx;
+        bottom * (1.0 - ty) + top * ty
+    }
+
+    /// Half the size, every pixel the average of the 2x2 block under it.
+    fn downsample(&self) -> Level {
+        let (w, h) = ((self.w / 2).max(1), (self.h / 2).max(1));
+        let mut pixels = Vec::with_capacity(w * h);
+        for y in 0 .. h {
+            for x in 0 .. w {
+                let at = |dx : usize, dy : usize| {
+                    let sx = (2 * x + dx).min(self.w - 1);
+                    let sy = (2 * y + dy).min(self.h - 1);
+                    self.pixels[sy * self.w + sx]
+                };
+                pixels.push((at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 4.0);
+            }
+        }
+        Level { w : w, h : h, pixels : pixels }
+    }
+}
+
+/// RGB image with channels in [0, 1], looked up by uv coordinates with v going up
+/// from the bottom row. Keeps a mip chain, every level half the size of the previous one.
+/// Values are used as they are stored, like `TextureMat` does.
+#[derive(Debug, PartialEq)]
+pub struct Texture {
+    pub w  : usize,
+    pub h  : usize,
+    levels : Vec<Level>
+}
+
+impl Texture {
+    /// `w * h` pixels, rows from top to bottom.
+    pub fn new(w : usize, h : usize, pixels : Vec<Vec3<f32>>) -> Texture {
+        assert_eq!(pixels.len(), w * h);
+        let mut levels = vec![Level { w : w, h : h, pixels : pixels }];
+        loop {
+            let next = match levels.last() {
+                Some(last) if last.w > 1 || last.h > 1 => last.downsample(),
+                _ => break
+            };
+            levels.push(next);
+        }
+        Texture { w : w, h : h, levels : levels }
+    }
+
+    pub fn load(path : &str) -> Result<Texture, String> {
+        let img = try!(image::open(path).map_err(|e| e.to_string())).to_rgb();
+        let (w, h) = (img.width() as usize, img.height() as usize);
+        let pixels = img.into_raw().chunks(3)
+            .map(|c| Vec3::new(c[0] as f32, c[1] as f32, c[2] as f32) / 255.0)
+            .collect();
+        Ok(Texture::new(w, h, pixels))
+    }
+
+    pub fn mip_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Bytes taken by the pixels of all the levels.
+    pub fn memory(&self) -> usize {
+        self.levels.iter().fold(0, |acc, l| acc + l.pixels.len() * size_of::<Vec3<f32>>())
+    }
+
+    /// Color at `uv`, `width` is the size of the pixel footprint in uv units.
+    /// Only trilinear filtering looks at the footprint.
+    pub fn lookup(&self, uv : Vec2<f32>, width : f32, wrap : Wrap, filter : TextureFilter) -> Vec3<f32> {
+        match filter {
+            TextureFilter::Nearest => self.levels[0].nearest(uv, wrap),
+            TextureFilter::Bilinear => self.levels[0].bilinear(uv, wrap),
+            TextureFilter::Trilinear => {
+                // level where one pixel covers the footprint
+                let last = (self.levels.len() - 1) as f32;
+                let level = (width * self.w.max(self.h) as f32).max(1.0).log2().min(last);
+                let lo = level.floor();
+                let t = level - lo;
+                let lo = lo as usize;
+                let color = self.levels[lo].bilinear(uv, wrap);
+                if t > 0.0 {
+                    color * (1.0 - t) + self.levels[lo + 1].bilinear(uv, wrap) * t
+                } else {
+                    color
+                }
+            }
+        }
+    }
+}
+
+/// Textures of a scene by path, so an image used by many objects is decoded and stored once.
+/// The textures are shared with `Arc`, the render threads read them all at once.
+pub struct TextureCache {
+    textures : HashMap<String, Arc<Texture>>
+}
+
+impl TextureCache {
+    pub fn new() -> TextureCache {
+        TextureCache { textures : HashMap::new() }
+    }
+
+    /// Texture of `path`, read from the file the first time only.
+    pub fn load(&mut self, path : &str) -> Result<Arc<Texture>, String> {
+        if let Some(texture) = self.textures.get(path) {
+            return Ok(texture.clone());
+        }
+        let texture = Arc::new(try!(Texture::load(path)));
+        self.textures.insert(path.to_string(), texture.clone());
+        Ok(texture)
+    }
+
+    /// Adds an already decoded texture under `path`.
+    pub fn insert(&mut self, path : &str, texture : Texture) -> Arc<Texture> {
+        let texture = Arc::new(texture);
+        self.textures.insert(path.to_string(), texture.clone());
+        texture
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    /// Bytes taken by all the textures, every one counted once however many objects use it.
+    pub fn memory(&self) -> usize {
+        self.textures.values().fold(0, |acc, t| acc + t.memory())
+    }
+}
+
+/// Placement and filtering of a texture on a surface. Texture coordinates are
+/// scaled, turned by `rotation` radians and then moved by `offset`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mapping {
+    pub scale    : Vec2<f32>,
+    pub rotation : f32,
+    pub offset   : Vec2<f32>,
+    pub wrap     : Wrap,
+    pub filter   : TextureFilter
+}
+
+impl Mapping {
+    pub fn new() -> Mapping {
+        Mapping {
+            scale    : Vec2::new(1.0, 1.0),
+            rotation : 0.0,
+            offset   : Vec2::new(0.0, 0.0),
+            wrap     : Wrap::Repeat,
+            filter   : TextureFilter::Trilinear
+        }
+    }
+
+    pub fn apply(&self, uv : Vec2<f32>) -> Vec2<f32> {
+        let (sin, cos) = self.rotation.sin_cos();
+        let uv = uv * self.scale;
+        Vec2::new(uv.x * cos - uv.y * sin, uv.x * sin + uv.y * cos) + self.offset
+    }
+}
+
+/// Shading parameter that is either constant or read from a texture at the hit.
+/// Single number parameters use the red channel.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Mapped {
+    Value(Vec3<f32>),
+    Texture(Arc<Texture>, Mapping)
+}
+
+impl Mapped {
+    /// Value at `uv`, `width` is the pixel footprint there in uv units, 0 if unknown.
+    pub fn at(&self, uv : Vec2<f32>, width : f32) -> Vec3<f32> {
+        match *self {
+            Mapped::Value(v) => v,
+            Mapped::Texture(ref texture, ref mapping) => {
+                let stretch = mapping.scale.x.abs().max(mapping.scale.y.abs());
+                texture.lookup(mapping.apply(uv), width * stretch, mapping.wrap, mapping.filter)
+            }
+        }
+    }
+
+    /// Whether `at` reads a mip level picked by the footprint, otherwise `width` is ignored
+    /// and callers can skip working it out.
+    pub fn needs_footprint(&self) -> bool {
+        match *self {
+            Mapped::Texture(_, ref mapping) => mapping.filter == TextureFilter::Trilinear,
+            Mapped::Value(_) => false
+        }
+    }
+}
+
+#[test]
+fn test_texture() {
+    let red = Vec3::new(1.0, 0.0, 0.0);
+    let blue = Vec3::new(0.0, 0.0, 1.0);
+    // red top row, blue bottom row
+    let texture = Texture::new(2, 2, vec![red, red, blue, blue]);
+    let nearest = |uv, wrap| texture.lookup(uv, 0.0, wrap, TextureFilter::Nearest);
+    assert_eq!(nearest(Vec2::new(0.25, 0.25), Wrap::Repeat), blue);
+    assert_eq!(nearest(Vec2::new(0.75, 0.75), Wrap::Repeat), red);
+    assert_eq!(nearest(Vec2::new(-0.25, 1.75), Wrap::Repeat), red);
+    assert_eq!(nearest(Vec2::new(1.0, 0.0), Wrap::Repeat), blue);
+    assert_eq!(nearest(Vec2::new(0.5, 1.25), Wrap::Clamp), red);
+    assert_eq!(nearest(Vec2::new(0.5, 1.25), Wrap::Mirror), red);
+    assert_eq!(nearest(Vec2::new(0.5, 1.75), Wrap::Mirror), blue);
+
+    // halfway between the rows, and the whole image far away
+    let mid = (red + blue) / 2.0;
+    assert!((texture.lookup(Vec2::new(0.5, 0.5), 0.0, Wrap::Clamp, TextureFilter::Bilinear) - mid).norm() < 0.0001);
+    assert_eq!(texture.mip_levels(), 2);
+    let far = texture.lookup(Vec2::new(0.25, 0.25), 1.0, Wrap::Repeat, TextureFilter::Trilinear);
+    assert!((far - mid).norm() < 0.0001);
+
+    let mut mapping = Mapping::new();
+    mapping.filter = TextureFilter::Nearest;
+    mapping.offset = Vec2::new(0.0, 0.5);
+    let mapped = Mapped::Texture(Arc::new(texture), mapping);
+    assert_eq!(mapped.at(Vec2::new(0.5, 0.25), 0.0), red);
+    assert_eq!(Mapped::Value(blue).at(Vec2::new(0.5, 0.9), 0.0), blue);
+}
+
+#[test]
+fn test_texture_cache() {
+    let gray = Vec3::new(0.5, 0.5, 0.5);
+    let mut cache = TextureCache::new();
+    let a = cache.insert("gray.png", Texture::new(4, 2, vec![gray; 8]));
+    let b = cache.load("gray.png").unwrap();
+    assert!(Arc::ptr_eq(&a, &b));
+    assert_eq!(cache.len(), 1);
+    // 4x2, 2x1 and 1x1 levels
+    assert_eq!(a.mip_levels(), 3);
+    assert_eq!(cache.memory(), 11 * size_of::<Vec3<f32>>());
+    assert!(cache.load("no such file.png").is_err());
+    assert_eq!(cache.len(), 1);
+}
@@ -0,0 +1,86 @@
+use core::*;
+use scene::*;
+use light::*;
+use surface::*;
+use sampling::*;
+use integrator::*;
+use bsdf::*;
+
+use std::f32::consts::PI;
+
+const MAX_DEPTH : i32 = 3;
+
+/// Direct light reflected by `bsdf` toward `wo`, `light_samples` shadow rays per area light
+/// and for the environment. Glossy surfaces leave the environment and glowing objects
+/// to their sampled reflection ray.
+fn direct(scene : &Scene, pos : Vec3<f32>, frame : &Frame, bsdf : &Bsdf, wo : Vec3<f32>,
+          light_samples : i32, sampler : &mut Sampler) -> Spectrum {
+    if bsdf.is_specular() {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    let glossy = bsdf.is_glossy();
+    let weight = |dir : Vec3<f32>| bsdf.eval(wo, frame.to_local(dir)) * PI;
+    let lights = scene.lights.iter().fold(Vec3::new(0.0, 0.0, 0.0), |acc, l| match *l {
+        Light::Emissive(..) if glossy => acc,
+        _ => acc + reflected_light(scene, l, pos, light_samples, sampler, &weight)
+    });
+    if glossy {
+        lights
+    } else {
+        lights + reflected_env(scene, pos, light_samples, sampler, &weight)
+    }
+}
+
+/// Direct light plus every specular lobe traced recursively, and one sampled ray for glossy surfaces.
+pub fn shade(scene : &Scene, ray : Ray, depth : i32, light_samples : i32, sampler : &mut Sampler) -> Spectrum {
+    let hit = match scene.objects.intersect_surface(ray) {
+        None => return match scene.env {
+            Some(ref env) => env.lookup(ray.dir) * 255.0,
+            None => Vec3::new(0.0, 0.0, 0.0)
+        },
+        Some(hit) => hit
+    };
+    let intersect = &hit.intersect;
+    let (frame, bsdf) = match surface_bsdf(&ray, &hit) {
+        Some(bsdf) => bsdf,
+        None => return match hit.surface {
+            Some(&Surface::Emissive { radiance, .. }) => radiance * 255.0,
+            _ => Vec3::new(0.0, 0.0, 0.0)
+        }
+    };
+    let wo = frame.to_local(ray.dir * -1.0);
+    let color = direct(scene, intersect.pos, &frame, &*bsdf, wo, light_samples, sampler) * 255.0;
+    if depth >= MAX_DEPTH {
+        return color;
+    }
+
+    let color = bsdf.specular(wo).into_iter().fold(color, |acc, (wi, weight)| {
+        let ray = ray.bounce(intersect.pos, frame.n, frame.to_world(wi));
+        acc + shade(scene, ray, depth + 1, light_samples, sampler) * weight
+    });
+    if !bsdf.is_glossy() {
+        return color;
+    }
+    let u = sampler.next_2d();
+    match bsdf.sample(wo, Vec3::new(u.x, u.y, sampler.next_1d())) {
+        Some(sample) => {
+            let dir = frame.to_world(sample.wi);
+            let ray = if sample.specular { ray.bounce(intersect.pos, frame.n, dir) } else { Ray::spawn(intersect.pos, dir) };
+            color + shade(scene, ray, depth + 1, light_samples, sampler) * sample.weight
+        }
+        None => color
+    }
+}
+
+/// Direct light with soft shadows plus mirror and dielectric bounces, see `shade`.
+#[derive(Clone, Copy, Debug)]
+pub struct Whitted {
+    /// Shadow rays per area light and hit.
+    pub light_samples : i32
+}
+
+impl Integrator for Whitted {
+    fn li(&self, scene : &Scene, ray : Ray, sampler : &mut Sampler) -> Spectrum {
+        shade(scene, ray, 0, self.light_samples, sampler)
+    }
+}
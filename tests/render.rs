@@ -0,0 +1,42 @@
+extern crate rays;
+
+use rays::*;
+use rays::scene::*;
+
+#[test]
+fn test_render_easy_scene() {
+    let scene = some_easy_scene(40, 30);
+    let settings = RenderSettings { threads : 2, .. RenderSettings::default() };
+    let image = render(&scene, &settings);
+    assert_eq!((image.w, image.h), (40, 30));
+    assert_eq!(image.data.len(), 40 * 30 * 3);
+
+    // camera looks straight down on the red sphere
+    let (r, g, b) = image.pixel(20, 15);
+    assert!(r > 0 && g == 0 && b == 0);
+}
+
+#[test]
+fn test_render_region() {
+    let scene = some_easy_scene(40, 30);
+    let settings = RenderSettings {
+        region : Some(Region { x : 10, y : 5, w : 20, h : 20 }),
+        .. RenderSettings::default()
+    };
+    let image = render(&scene, &settings);
+    assert_eq!((image.w, image.h), (20, 20));
+    let (r, _, _) = image.pixel(10, 10);
+    assert!(r > 0);
+}
+
+#[test]
+fn test_render_with_integrator() {
+    let scene = some_easy_scene(40, 30);
+    let image = render_with(&scene, &Normals, &RenderSettings::default());
+    // top of the sphere faces the camera, normal (0, 1, 0) is green
+    let (r, g, b) = image.pixel(20, 15);
+    assert!(g > r && g > b);
+
+    let image = render_with(&scene, &AmbientOcclusion { distance : 1.0, samples : 4 }, &RenderSettings::default());
+    assert_eq!(image.data.len(), 40 * 30 * 3);
+}